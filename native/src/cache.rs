@@ -0,0 +1,686 @@
+//! HTTP cache following RFC 9111. Only `GET` responses are stored. Stale
+//! responses with a validator (`ETag` or `Last-Modified`) are revalidated with
+//! a conditional request. The least recently used responses are evicted once
+//! the total size exceeds `max_bytes`. Bodies are kept in memory, or in `dir`
+//! (see `disk_cache`) so that they survive restarts.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE,
+    ETAG, EXPIRES, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE,
+    LAST_MODIFIED, PRAGMA, VARY,
+};
+use reqwest::{Method, StatusCode, Url};
+use rustler::{Atom, Encoder, Env, NifResult, NifUnitEnum, Term};
+
+use crate::atoms;
+use crate::disk_cache::{self, Meta, TmpFile};
+
+/// Reported as `cache` in the response
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Revalidated,
+}
+
+/// A response as it is stored, or served from the cache
+pub struct Stored {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+pub enum Lookup {
+    /// A fresh response, to be used without contacting the server
+    Hit(Stored),
+    /// The request must be sent (possibly made conditional), and the response
+    /// passed to `Cache::respond`
+    Forward(Pending),
+}
+
+pub enum Outcome {
+    /// The response was a `304` to a revalidation, use the stored response
+    /// instead
+    Revalidated(Stored),
+    /// The response can be stored once its body has been read
+    Store(Box<Writer>),
+    Miss,
+}
+
+/// Collects the body of a response to be stored. Nothing is stored unless
+/// `finish` is called, eg. if the body is not read to the end.
+pub struct Writer {
+    cache: Arc<Cache>,
+    key: String,
+    request_headers: HeaderMap,
+    meta: Meta,
+    size: usize,
+    /// `None` once the body turned out to be too large or couldn't be written
+    sink: Option<Sink>,
+}
+
+enum Sink {
+    Memory(Vec<Bytes>),
+    Disk(TmpFile, PathBuf),
+}
+
+pub struct Pending {
+    key: String,
+    request_headers: HeaderMap,
+    /// Whether `Authorization` is added to the request after the lookup
+    authenticated: bool,
+    /// Whether the request allows the response to be stored
+    store: bool,
+    /// Whether validators from a stored response were added to the request
+    revalidating: bool,
+}
+
+impl Stored {
+    /// Serve the response as if it came from the server
+    pub fn into_response(self) -> reqwest::Response {
+        let mut resp = http::Response::new(self.body);
+        *resp.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        *resp.headers_mut() = self.headers;
+        resp.into()
+    }
+}
+
+struct Entry {
+    meta: Meta,
+    body: Body,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Clone)]
+enum Body {
+    Memory(Bytes),
+    Disk(PathBuf),
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Vec<Entry>>,
+    size: usize,
+    tick: u64,
+}
+
+pub struct Cache {
+    max_bytes: usize,
+    /// A shared cache doesn't store `private` responses, or responses to
+    /// requests with `Authorization` unless explicitly allowed
+    shared: bool,
+    /// Where bodies are stored, if not in memory
+    dir: Option<PathBuf>,
+    store: Mutex<Store>,
+}
+
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl Cache {
+    pub fn decode(env: Env, term: Term) -> NifResult<Cache> {
+        let get = |k: Atom| term.map_get(k.encode(env)).ok();
+        let mut cache = Cache {
+            max_bytes: match get(atoms::max_bytes()) {
+                Some(v) => v.decode()?,
+                None => 64 * 1024 * 1024,
+            },
+            shared: match get(atoms::shared()) {
+                Some(v) => v.decode()?,
+                None => false,
+            },
+            dir: None,
+            store: Mutex::new(Store::default()),
+        };
+        if let Some(v) = get(atoms::dir()) {
+            let dir: String = v.decode()?;
+            cache.load(PathBuf::from(&dir)).map_err(|e| {
+                rustler::Error::RaiseTerm(Box::new((
+                    atoms::client_builder_error(),
+                    format!("{}: {}", dir, e),
+                )))
+            })?;
+        }
+        Ok(cache)
+    }
+
+    /// Use `dir` for storage, picking up the responses stored there by
+    /// earlier clients
+    fn load(&mut self, dir: PathBuf) -> io::Result<()> {
+        let mut loaded = disk_cache::load(&dir)?;
+        loaded.sort_by_key(|l| l.last_used);
+        let store = self.store.get_mut().unwrap();
+        for l in loaded {
+            store.tick += 1;
+            let entry = Entry {
+                size: l.body_len + headers_size(&l.meta.headers),
+                meta: l.meta,
+                body: Body::Disk(l.path),
+                last_used: store.tick,
+            };
+            store.size += entry.size;
+            store.entries.entry(l.key).or_default().push(entry);
+        }
+        while store.size > self.max_bytes {
+            store.evict_lru();
+        }
+        self.dir = Some(dir);
+        Ok(())
+    }
+
+    /// Requests with unsafe methods invalidate the stored responses for their
+    /// URL
+    pub fn invalidate(&self, request: &reqwest::Request) {
+        if !is_safe(request.method()) {
+            self.purge(&cache_key(request.url()));
+        }
+    }
+
+    /// Look up a `GET` request, adding validators to it if a stale response
+    /// can be revalidated. Returns `None` for other methods, in which case
+    /// the response is not stored. `authenticated` means that credentials
+    /// (eg. from `oauth2` or `digest_auth`) are added when the request is sent.
+    pub async fn lookup(
+        &self,
+        request: &mut reqwest::Request,
+        authenticated: bool,
+    ) -> Option<Lookup> {
+        self.invalidate(request);
+        if *request.method() != Method::GET {
+            return None;
+        }
+        let key = cache_key(request.url());
+        let cc = request_cache_control(request.headers());
+        let mut pending = Pending {
+            key,
+            request_headers: request.headers().clone(),
+            authenticated: authenticated || request.headers().contains_key(AUTHORIZATION),
+            store: !cc.no_store,
+            revalidating: false,
+        };
+        // the caller is doing their own revalidation
+        if cc.no_store
+            || [
+                IF_MATCH,
+                IF_NONE_MATCH,
+                IF_MODIFIED_SINCE,
+                IF_UNMODIFIED_SINCE,
+                IF_RANGE,
+            ]
+            .iter()
+            .any(|h| request.headers().contains_key(h))
+        {
+            return Some(Lookup::Forward(pending));
+        }
+        let now = SystemTime::now();
+        let (mut stored, body) = {
+            let mut store = self.store.lock().unwrap();
+            store.tick += 1;
+            let tick = store.tick;
+            let entry = match store
+                .entries
+                .get_mut(&pending.key)
+                .and_then(|variants| variants.iter_mut().find(|e| e.matches(request.headers())))
+            {
+                Some(entry) => entry,
+                None => return Some(Lookup::Forward(pending)),
+            };
+            entry.last_used = tick;
+            let meta = &entry.meta;
+            let age = entry.age(now);
+            let fresh = age < meta.lifetime
+                && !cc.no_cache
+                && !parse_cache_control(&meta.headers).no_cache
+                && cc.max_age.is_none_or(|max_age| age.as_secs() <= max_age);
+            if !fresh {
+                if let Some(etag) = meta.headers.get(ETAG) {
+                    request.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+                    pending.revalidating = true;
+                }
+                if let Some(last_modified) = meta.headers.get(LAST_MODIFIED) {
+                    request
+                        .headers_mut()
+                        .insert(IF_MODIFIED_SINCE, last_modified.clone());
+                    pending.revalidating = true;
+                }
+                return Some(Lookup::Forward(pending));
+            }
+            let mut headers = meta.headers.clone();
+            headers.insert(AGE, HeaderValue::from(age.as_secs()));
+            let stored = Stored {
+                status: meta.status,
+                headers,
+                body: Bytes::new(),
+            };
+            (stored, entry.body.clone())
+        };
+        // read outside of the lock
+        match self.read(&pending.key, body).await {
+            Some(body) => {
+                stored.body = body;
+                Some(Lookup::Hit(stored))
+            }
+            None => Some(Lookup::Forward(pending)),
+        }
+    }
+
+    /// Called with the response to a forwarded request. If it is a `304` to a
+    /// revalidation, the stored response is freshened and returned so that it
+    /// can be used instead.
+    pub async fn respond(
+        self: &Arc<Self>,
+        pending: Pending,
+        status: u16,
+        headers: &HeaderMap,
+    ) -> Outcome {
+        let now = SystemTime::now();
+        if status == StatusCode::NOT_MODIFIED && pending.revalidating {
+            return match self.freshen(&pending, headers, now).await {
+                Some(stored) => Outcome::Revalidated(stored),
+                None => Outcome::Miss,
+            };
+        }
+        if !pending.store || !self.storable(status, headers, pending.authenticated) {
+            return Outcome::Miss;
+        }
+        let vary = match vary_names(headers) {
+            Some(names) => names
+                .into_iter()
+                .map(|name| {
+                    let values = pending
+                        .request_headers
+                        .get_all(&name)
+                        .iter()
+                        .cloned()
+                        .collect();
+                    (name, values)
+                })
+                .collect(),
+            // `Vary: *` never matches
+            None => return Outcome::Miss,
+        };
+        let size = headers_size(headers);
+        if size > self.max_bytes {
+            return Outcome::Miss;
+        }
+        let meta = Meta {
+            status,
+            headers: headers.clone(),
+            vary,
+            response_time: now,
+            initial_age: initial_age(headers, now),
+            lifetime: self.lifetime(status, headers, now).unwrap_or_default(),
+        };
+        let sink = match &self.dir {
+            Some(dir) => {
+                let path = disk_cache::path(dir, &pending.key, &meta.vary);
+                match TmpFile::create(&path, &pending.key, &meta).await {
+                    Ok(tmp) => Sink::Disk(tmp, path),
+                    Err(_) => return Outcome::Miss,
+                }
+            }
+            None => Sink::Memory(Vec::new()),
+        };
+        Outcome::Store(Box::new(Writer {
+            cache: self.clone(),
+            key: pending.key,
+            request_headers: pending.request_headers,
+            meta,
+            size,
+            sink: Some(sink),
+        }))
+    }
+
+    async fn freshen(
+        &self,
+        pending: &Pending,
+        headers: &HeaderMap,
+        now: SystemTime,
+    ) -> Option<Stored> {
+        let (meta, body) = {
+            let mut store = self.store.lock().unwrap();
+            store.tick += 1;
+            let tick = store.tick;
+            let entry = store
+                .entries
+                .get_mut(&pending.key)?
+                .iter_mut()
+                .find(|e| e.matches(&pending.request_headers))?;
+            let meta = &mut entry.meta;
+            for (name, value) in headers {
+                if name != CONTENT_LENGTH {
+                    meta.headers.insert(name, value.clone());
+                }
+            }
+            meta.response_time = now;
+            meta.initial_age = initial_age(&meta.headers, now);
+            meta.lifetime = self
+                .lifetime(meta.status, &meta.headers, now)
+                .unwrap_or_default();
+            entry.last_used = tick;
+            (entry.meta.clone(), entry.body.clone())
+        };
+        let body = match body {
+            Body::Disk(path) => match disk_cache::rewrite(&path, &pending.key, &meta).await {
+                Ok(body) => body,
+                Err(_) => {
+                    self.discard(&pending.key, &path);
+                    return None;
+                }
+            },
+            Body::Memory(body) => body,
+        };
+        Some(Stored {
+            status: meta.status,
+            headers: meta.headers,
+            body,
+        })
+    }
+
+    /// Read a stored body. If its file has gone missing the entry is removed.
+    async fn read(&self, key: &str, body: Body) -> Option<Bytes> {
+        match body {
+            Body::Memory(body) => Some(body),
+            Body::Disk(path) => match disk_cache::read_body(&path).await {
+                Ok(body) => Some(body),
+                Err(_) => {
+                    self.discard(key, &path);
+                    None
+                }
+            },
+        }
+    }
+
+    fn discard(&self, key: &str, path: &PathBuf) {
+        self.store
+            .lock()
+            .unwrap()
+            .remove(key, |e| matches!(&e.body, Body::Disk(p) if p == path));
+    }
+
+    /// Remove all stored responses for a URL
+    pub fn purge(&self, key: &str) {
+        self.store.lock().unwrap().remove(key, |_| true);
+    }
+
+    fn storable(&self, status: u16, headers: &HeaderMap, authenticated: bool) -> bool {
+        let cc = parse_cache_control(headers);
+        if cc.no_store || (self.shared && cc.private) {
+            return false;
+        }
+        if self.shared
+            && authenticated
+            && !(cc.public || cc.must_revalidate || cc.s_maxage.is_some())
+        {
+            return false;
+        }
+        let explicit = cc.max_age.is_some()
+            || (self.shared && cc.s_maxage.is_some())
+            || headers.contains_key(EXPIRES);
+        // partial content is not supported
+        status != StatusCode::PARTIAL_CONTENT
+            && (explicit || cc.public || heuristically_cacheable(status))
+    }
+
+    /// `None` if there is no explicit lifetime and no heuristic applies
+    fn lifetime(&self, status: u16, headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+        let cc = parse_cache_control(headers);
+        if let Some(s_maxage) = cc.s_maxage.filter(|_| self.shared) {
+            return Some(Duration::from_secs(s_maxage));
+        }
+        if let Some(max_age) = cc.max_age {
+            return Some(Duration::from_secs(max_age));
+        }
+        let date = http_date(headers, DATE).unwrap_or(now);
+        if headers.contains_key(EXPIRES) {
+            // an invalid date means "already expired"
+            return Some(
+                http_date(headers, EXPIRES)
+                    .and_then(|expires| expires.duration_since(date).ok())
+                    .unwrap_or_default(),
+            );
+        }
+        if cc.public || heuristically_cacheable(status) {
+            let last_modified = http_date(headers, LAST_MODIFIED)?;
+            return Some(date.duration_since(last_modified).ok()? / 10);
+        }
+        None
+    }
+}
+
+impl Writer {
+    pub async fn write(&mut self, chunk: &Bytes) {
+        self.size += chunk.len();
+        if self.size > self.cache.max_bytes {
+            self.sink = None;
+        }
+        let ok = match &mut self.sink {
+            Some(Sink::Memory(chunks)) => {
+                chunks.push(chunk.clone());
+                true
+            }
+            Some(Sink::Disk(tmp, _)) => tmp.write(chunk).await.is_ok(),
+            None => true,
+        };
+        if !ok {
+            self.sink = None;
+        }
+    }
+
+    /// Store the response, called once the whole body has been written
+    pub async fn finish(self) {
+        let body = match self.sink {
+            Some(Sink::Memory(chunks)) if chunks.len() == 1 => {
+                Body::Memory(chunks.into_iter().next().unwrap())
+            }
+            Some(Sink::Memory(chunks)) => Body::Memory(chunks.concat().into()),
+            Some(Sink::Disk(tmp, path)) => match tmp.commit().await {
+                Ok(()) => Body::Disk(path),
+                Err(_) => return,
+            },
+            None => return,
+        };
+        let cache = &self.cache;
+        let mut store = cache.store.lock().unwrap();
+        store.tick += 1;
+        let entry = Entry {
+            meta: self.meta,
+            body,
+            size: self.size,
+            last_used: store.tick,
+        };
+        store.insert(self.key, entry, &self.request_headers);
+        while store.size > cache.max_bytes {
+            store.evict_lru();
+        }
+    }
+}
+
+impl Store {
+    /// Add an entry, replacing the one for the same variant
+    fn insert(&mut self, key: String, entry: Entry, request_headers: &HeaderMap) {
+        // the new body may already have replaced the old file
+        let keep = match &entry.body {
+            Body::Disk(path) => Some(path.clone()),
+            Body::Memory(_) => None,
+        };
+        self.remove_except(&key, |e| e.matches(request_headers), keep.as_ref());
+        self.size += entry.size;
+        self.entries.entry(key).or_default().push(entry);
+    }
+
+    fn remove(&mut self, key: &str, f: impl Fn(&Entry) -> bool) {
+        self.remove_except(key, f, None)
+    }
+
+    /// Remove entries and their files, except for the file at `keep`
+    fn remove_except(&mut self, key: &str, f: impl Fn(&Entry) -> bool, keep: Option<&PathBuf>) {
+        if let Some(variants) = self.entries.get_mut(key) {
+            let mut removed = 0;
+            variants.retain(|e| {
+                if !f(e) {
+                    return true;
+                }
+                removed += e.size;
+                if let Body::Disk(path) = &e.body {
+                    if Some(path) != keep {
+                        disk_cache::remove(path);
+                    }
+                }
+                false
+            });
+            if variants.is_empty() {
+                self.entries.remove(key);
+            }
+            self.size -= removed;
+        }
+    }
+
+    fn evict_lru(&mut self) {
+        let lru = self
+            .entries
+            .iter()
+            .flat_map(|(key, variants)| variants.iter().map(move |e| (e.last_used, key)))
+            .min()
+            .map(|(last_used, key)| (last_used, key.clone()));
+        match lru {
+            Some((last_used, key)) => self.remove(&key, |e| e.last_used == last_used),
+            None => self.size = 0,
+        }
+    }
+}
+
+impl Entry {
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.meta
+            .vary
+            .iter()
+            .all(|(name, values)| request_headers.get_all(name).iter().eq(values.iter()))
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        self.meta.initial_age
+            + now
+                .duration_since(self.meta.response_time)
+                .unwrap_or_default()
+    }
+}
+
+pub fn cache_key(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.into()
+}
+
+fn headers_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(k, v)| k.as_str().len() + v.len())
+        .sum()
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Status codes which are cacheable without explicit freshness information
+fn heuristically_cacheable(status: u16) -> bool {
+    matches!(
+        status,
+        200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+/// `None` for `Vary: *`
+fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for value in headers.get_all(VARY) {
+        for name in value.to_str().unwrap_or("").split(',').map(str::trim) {
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                names.push(name);
+            }
+        }
+    }
+    Some(names)
+}
+
+fn initial_age(headers: &HeaderMap, now: SystemTime) -> Duration {
+    let age = headers
+        .get(AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let apparent_age = http_date(headers, DATE)
+        .and_then(|date| now.duration_since(date).ok())
+        .unwrap_or_default();
+    age.max(apparent_age)
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+fn request_cache_control(headers: &HeaderMap) -> CacheControl {
+    let mut cc = parse_cache_control(headers);
+    // `Pragma: no-cache` is only used if there is no `Cache-Control`
+    if !headers.contains_key(CACHE_CONTROL)
+        && headers
+            .get_all(PRAGMA)
+            .iter()
+            .any(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+    {
+        cc.no_cache = true;
+    }
+    cc
+}
+
+fn parse_cache_control(headers: &HeaderMap) -> CacheControl {
+    let mut cc = CacheControl::default();
+    for value in headers.get_all(CACHE_CONTROL) {
+        for directive in value.to_str().unwrap_or("").split(',') {
+            let (name, arg) = match directive.find('=') {
+                Some(i) => (
+                    &directive[..i],
+                    Some(directive[i + 1..].trim().trim_matches('"')),
+                ),
+                None => (directive, None),
+            };
+            let seconds = || arg.and_then(|a| a.parse().ok());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                // an invalid max-age means the response is stale
+                "max-age" => cc.max_age = Some(seconds().unwrap_or(0)),
+                "s-maxage" => cc.s_maxage = Some(seconds().unwrap_or(0)),
+                _ => (),
+            }
+        }
+    }
+    cc
+}
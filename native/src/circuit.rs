@@ -0,0 +1,238 @@
+//! Per-host circuit breaker. Once `failure_threshold` requests to a host have
+//! failed within `window`, requests to it fail immediately for
+//! `open_duration`. After that up to `half_open_requests` trial requests are
+//! let through: the circuit closes once they have all succeeded, and opens
+//! again as soon as one of them fails.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use rustler::{Atom, Encoder, Env, NifMap, NifResult, NifUnitEnum, Term};
+
+use crate::atoms;
+use crate::req::{Error, ErrorCode};
+use crate::utils::host_key;
+
+struct Config {
+    failure_threshold: u32,
+    window: Duration,
+    open_duration: Duration,
+    half_open_requests: u32,
+}
+
+enum State {
+    Closed { failures: VecDeque<Instant> },
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, successes: u32 },
+}
+
+#[derive(NifUnitEnum)]
+enum StateName {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// The per-host state as reported by `erqwest:circuit_state/1`
+#[derive(NifMap)]
+pub struct HostState {
+    state: StateName,
+    /// Failures within the window, only counted while the circuit is closed
+    failures: u32,
+}
+
+pub struct CircuitBreaker {
+    config: Config,
+    hosts: Mutex<HashMap<String, State>>,
+}
+
+/// Permission to send a request. Dropping it without calling `record` (eg.
+/// because the request was cancelled) frees up its trial slot.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    host: String,
+    trial: bool,
+    success: Option<bool>,
+}
+
+impl CircuitBreaker {
+    pub fn decode(env: Env, term: Term) -> NifResult<CircuitBreaker> {
+        let get = |k: Atom| term.map_get(k.encode(env)).ok();
+        let ms = |t: Term| t.decode().map(Duration::from_millis);
+        let mut config = Config {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(30),
+            half_open_requests: 1,
+        };
+        if let Some(v) = get(atoms::failure_threshold()) {
+            config.failure_threshold = v.decode()?;
+        }
+        if let Some(v) = get(atoms::window()) {
+            config.window = ms(v)?;
+        }
+        if let Some(v) = get(atoms::open_duration()) {
+            config.open_duration = ms(v)?;
+        }
+        if let Some(v) = get(atoms::half_open_requests()) {
+            config.half_open_requests = v.decode()?;
+        }
+        if config.failure_threshold == 0 || config.half_open_requests == 0 {
+            return Err(rustler::Error::BadArg);
+        }
+        Ok(CircuitBreaker {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn acquire(&self, url: &Url) -> Result<Permit<'_>, Error> {
+        let host = host_key(url);
+        let mut hosts = self.hosts.lock().unwrap();
+        if !hosts.contains_key(&host) {
+            // forget hosts that have nothing to remember, so that talking to
+            // many hosts doesn't grow the map without bound
+            let now = Instant::now();
+            hosts.retain(|_, state| match state {
+                State::Closed { failures } => {
+                    prune(failures, now, self.config.window);
+                    !failures.is_empty()
+                }
+                _ => true,
+            });
+        }
+        let state = hosts.entry(host.clone()).or_insert_with(State::closed);
+        if let State::Open { until } = *state {
+            if Instant::now() >= until {
+                *state = State::HalfOpen {
+                    in_flight: 0,
+                    successes: 0,
+                };
+            }
+        }
+        let trial = match state {
+            State::Closed { .. } => false,
+            State::HalfOpen {
+                in_flight,
+                successes,
+            } if *in_flight + *successes < self.config.half_open_requests => {
+                *in_flight += 1;
+                true
+            }
+            _ => {
+                return Err(Error::from_reason(
+                    ErrorCode::CircuitOpen,
+                    format!("circuit open for {}", host),
+                ))
+            }
+        };
+        Ok(Permit {
+            breaker: self,
+            host,
+            trial,
+            success: None,
+        })
+    }
+
+    pub fn states(&self) -> HashMap<String, HostState> {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .iter_mut()
+            .map(|(host, state)| {
+                let (state, failures) = match state {
+                    State::Closed { failures } => {
+                        prune(failures, now, self.config.window);
+                        (StateName::Closed, failures.len() as u32)
+                    }
+                    State::Open { until } if now < *until => (StateName::Open, 0),
+                    _ => (StateName::HalfOpen, 0),
+                };
+                (host.clone(), HostState { state, failures })
+            })
+            .collect()
+    }
+}
+
+impl State {
+    fn closed() -> State {
+        State::Closed {
+            failures: VecDeque::new(),
+        }
+    }
+}
+
+impl<'a> Permit<'a> {
+    /// Connection errors, timeouts and 5xx responses count as failures
+    pub fn record(mut self, result: Result<&reqwest::Response, &Error>) {
+        self.success = Some(match result {
+            Ok(resp) => !resp.status().is_server_error(),
+            Err(e) => !matches!(e.code, ErrorCode::Connect | ErrorCode::Timeout),
+        });
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let config = &self.breaker.config;
+        let mut hosts = self.breaker.hosts.lock().unwrap();
+        // a closed host may have been evicted while this request was in
+        // flight, in which case its failure still counts
+        let state = if self.trial {
+            match hosts.get_mut(&self.host) {
+                Some(state) => state,
+                None => return,
+            }
+        } else if self.success == Some(false) {
+            hosts.entry(self.host.clone()).or_insert_with(State::closed)
+        } else {
+            return;
+        };
+        let now = Instant::now();
+        let open = State::Open {
+            until: now + config.open_duration,
+        };
+        let next = match state {
+            State::Closed { failures } if self.success == Some(false) => {
+                failures.push_back(now);
+                prune(failures, now, config.window);
+                if failures.len() as u32 >= config.failure_threshold {
+                    Some(open)
+                } else {
+                    None
+                }
+            }
+            State::HalfOpen {
+                in_flight,
+                successes,
+            } if self.trial => {
+                *in_flight -= 1;
+                match self.success {
+                    Some(true) => {
+                        *successes += 1;
+                        if *successes >= config.half_open_requests {
+                            Some(State::closed())
+                        } else {
+                            None
+                        }
+                    }
+                    Some(false) => Some(open),
+                    None => None,
+                }
+            }
+            // requests that were sent before the state changed don't count
+            _ => None,
+        };
+        if let Some(next) = next {
+            *state = next;
+        }
+    }
+}
+
+fn prune(failures: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while matches!(failures.front(), Some(t) if now.duration_since(*t) > window) {
+        failures.pop_front();
+    }
+}
@@ -1,9 +1,20 @@
-use std::sync::RwLock;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 use reqwest::{Certificate, Identity};
 use rustler::{Atom, Encoder, Env, MapIterator, NifMap, NifResult, NifUnitEnum, ResourceArc, Term};
 use rustler::{Binary, ListIterator};
 
+use crate::cache::{self, Cache};
+use crate::circuit::{CircuitBreaker, HostState};
+use crate::coalesce::Coalescer;
+use crate::digest::NonceCache;
+use crate::limit::{Limits, MAX_PERMITS};
+use crate::netrc::Netrc;
+use crate::oauth2::{self, OAuth2};
+use crate::rate_limit::RateLimit;
+use crate::req::Coalesced;
+use crate::retry::RetryPolicy;
 use crate::utils::maybe_timeout;
 use crate::{atoms, runtime::RuntimeResource};
 
@@ -25,9 +36,25 @@ struct ProxySpecBase {
     url: String,
 }
 
+#[derive(NifMap)]
+struct OAuth2Base {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+}
+
 pub struct ClientResource {
     pub client: RwLock<Option<reqwest::Client>>,
     pub runtime: ResourceArc<RuntimeResource>,
+    pub digest_nonces: Arc<NonceCache>,
+    pub oauth2: Option<Arc<OAuth2>>,
+    pub netrc: Option<Arc<Netrc>>,
+    pub retry: Option<RetryPolicy>,
+    pub circuit: Option<Arc<CircuitBreaker>>,
+    pub limits: Option<Arc<Limits>>,
+    pub rate_limit: Option<Arc<RateLimit>>,
+    pub coalescer: Arc<Coalescer<Coalesced>>,
+    pub cache: Option<Arc<Cache>>,
 }
 
 // This is marked as "dirty" because it can take quite a while (around 30 ms according to
@@ -43,6 +70,15 @@ fn make_client(
         return Err(rustler::Error::BadArg);
     }
     let mut builder = reqwest::ClientBuilder::new();
+    let mut oauth2 = None;
+    let mut netrc = None;
+    let mut retry = None;
+    let mut circuit = None;
+    let mut max_in_flight = None;
+    let mut max_in_flight_per_host = None;
+    let mut queue_timeout = None;
+    let mut rate_limit = None;
+    let mut cache = None;
     for (k, v) in opts.decode::<MapIterator>()? {
         let k: Atom = k.decode()?;
         if k == atoms::identity() {
@@ -89,6 +125,10 @@ fn make_client(
             builder = builder.pool_max_idle_per_host(v.decode()?);
         } else if k == atoms::https_only() {
             builder = builder.https_only(v.decode()?);
+        } else if k == atoms::http2_prior_knowledge() {
+            if v.decode()? {
+                builder = builder.http2_prior_knowledge();
+            }
         } else if k == atoms::cookie_store() {
             #[cfg(feature = "cookies")]
             {
@@ -131,6 +171,54 @@ fn make_client(
                     }
                 }
             }
+        } else if k == atoms::oauth2() {
+            let OAuth2Base {
+                token_url,
+                client_id,
+                client_secret,
+            } = v.decode()?;
+            let scope = match v.map_get(atoms::scope().encode(env)) {
+                Ok(term) => Some(term.decode()?),
+                Err(_) => None,
+            };
+            oauth2 = Some(Arc::new(OAuth2::new(oauth2::Config {
+                token_url: reqwest::Url::parse(&token_url).map_err(|_| rustler::Error::BadArg)?,
+                client_id,
+                client_secret,
+                scope,
+            })));
+        } else if k == atoms::retry() {
+            retry = RetryPolicy::decode(env, v, None)?;
+        } else if k == atoms::max_in_flight() {
+            max_in_flight = Some(v.decode()?);
+        } else if k == atoms::max_in_flight_per_host() {
+            max_in_flight_per_host = Some(v.decode()?);
+        } else if k == atoms::queue_timeout() {
+            queue_timeout = maybe_timeout(v)?;
+        } else if k == atoms::cache() {
+            cache = Some(Arc::new(Cache::decode(env, v)?));
+        } else if k == atoms::rate_limit() {
+            rate_limit = Some(Arc::new(RateLimit::decode(env, v)?));
+        } else if k == atoms::circuit_breaker() {
+            circuit = Some(Arc::new(CircuitBreaker::decode(env, v)?));
+        } else if k == atoms::netrc() {
+            netrc = match v.decode::<bool>() {
+                Ok(false) => None,
+                // like curl, a missing ~/.netrc is not an error
+                Ok(true) => Netrc::default_path()
+                    .and_then(|path| Netrc::read(&path).ok())
+                    .map(Arc::new),
+                Err(_) => {
+                    let path: String = v.decode()?;
+                    let netrc = Netrc::read(path.as_ref()).map_err(|e| {
+                        rustler::Error::RaiseTerm(Box::new((
+                            atoms::client_builder_error(),
+                            format!("{}: {}", path, e),
+                        )))
+                    })?;
+                    Some(Arc::new(netrc))
+                }
+            };
         } else {
             return Err(rustler::Error::RaiseTerm(Box::new((atoms::bad_opt(), k))));
         }
@@ -138,12 +226,51 @@ fn make_client(
     let client = builder.build().map_err(|e| {
         rustler::Error::RaiseTerm(Box::new((atoms::client_builder_error(), e.to_string())))
     })?;
+    let valid_limit = |n: Option<usize>| n.is_none_or(|n| n > 0 && n <= MAX_PERMITS);
+    if !valid_limit(max_in_flight) || !valid_limit(max_in_flight_per_host) {
+        return Err(rustler::Error::BadArg);
+    }
+    let limits = if max_in_flight.is_some() || max_in_flight_per_host.is_some() {
+        Some(Arc::new(Limits::new(
+            max_in_flight,
+            max_in_flight_per_host,
+            queue_timeout,
+        )))
+    } else {
+        None
+    };
     Ok(ResourceArc::new(ClientResource {
         client: RwLock::new(Some(client)),
         runtime,
+        digest_nonces: Arc::new(NonceCache::default()),
+        oauth2,
+        netrc,
+        retry,
+        circuit,
+        limits,
+        rate_limit,
+        coalescer: Arc::new(Coalescer::default()),
+        cache,
     }))
 }
 
+#[rustler::nif]
+fn circuit_state(resource: ResourceArc<ClientResource>) -> HashMap<String, HostState> {
+    match &resource.circuit {
+        Some(circuit) => circuit.states(),
+        None => HashMap::new(),
+    }
+}
+
+#[rustler::nif]
+fn cache_purge(resource: ResourceArc<ClientResource>, url: &str) -> NifResult<Atom> {
+    let url = reqwest::Url::parse(url).map_err(|_| rustler::Error::BadArg)?;
+    if let Some(cache) = &resource.cache {
+        cache.purge(&cache::cache_key(&url));
+    }
+    Ok(atoms::ok())
+}
+
 #[rustler::nif]
 fn close_client(resource: ResourceArc<ClientResource>) -> NifResult<Atom> {
     if resource.client.write().unwrap().take().is_some() {
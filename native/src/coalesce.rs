@@ -0,0 +1,115 @@
+//! Coalescing of identical concurrent requests: the first request for a key
+//! (the leader) is sent, and the requests which arrive while it is in flight
+//! (the followers) share its result.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use reqwest::header::HeaderName;
+
+pub type Key = Vec<u8>;
+
+pub struct Coalescer<T> {
+    followers: Mutex<HashMap<Key, Vec<oneshot::Sender<T>>>>,
+}
+
+pub enum Role<T> {
+    Leader(Leader<T>),
+    /// Receives the leader's result. If the leader is cancelled, this is
+    /// cancelled too and the follower should `join` again.
+    Follower(oneshot::Receiver<T>),
+}
+
+/// Removes the key when dropped, so that the next request becomes a leader
+pub struct Leader<T> {
+    coalescer: Arc<Coalescer<T>>,
+    /// `None` once finished
+    key: Option<Key>,
+}
+
+/// Which request headers must be identical for requests to be coalesced
+pub enum KeyHeaders {
+    All,
+    Only(Vec<HeaderName>),
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Coalescer {
+            followers: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    pub fn join(self: &Arc<Self>, key: Key) -> Role<T> {
+        let mut followers = self.followers.lock().unwrap();
+        match followers.get_mut(&key) {
+            Some(waiting) => {
+                let (tx, rx) = oneshot::channel();
+                waiting.push(tx);
+                Role::Follower(rx)
+            }
+            None => {
+                followers.insert(key.clone(), Vec::new());
+                Role::Leader(Leader {
+                    coalescer: self.clone(),
+                    key: Some(key),
+                })
+            }
+        }
+    }
+}
+
+impl<T: Clone> Leader<T> {
+    pub fn finish(mut self, result: T) {
+        let key = self.key.take().unwrap();
+        let waiting = self
+            .coalescer
+            .followers
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .unwrap_or_default();
+        for tx in waiting {
+            // the follower may have been cancelled
+            let _ = tx.send(result.clone());
+        }
+    }
+}
+
+impl<T> Drop for Leader<T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.coalescer.followers.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl KeyHeaders {
+    pub fn key(&self, request: &reqwest::Request) -> Key {
+        let mut key = format!("{} {}\n", request.method(), request.url()).into_bytes();
+        let mut headers: Vec<_> = match self {
+            KeyHeaders::All => request.headers().iter().collect(),
+            KeyHeaders::Only(names) => names
+                .iter()
+                .flat_map(|name| {
+                    request
+                        .headers()
+                        .get_all(name)
+                        .iter()
+                        .map(move |v| (name, v))
+                })
+                .collect(),
+        };
+        headers.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        for (name, value) in headers {
+            key.extend_from_slice(name.as_str().as_bytes());
+            key.extend_from_slice(b": ");
+            key.extend_from_slice(value.as_bytes());
+            key.push(b'\n');
+        }
+        key
+    }
+}
@@ -0,0 +1,249 @@
+//! HTTP Digest authentication (RFC 7616). Only `qop=auth` (or no qop, as in
+//! RFC 2069) is supported, since `auth-int` would require hashing the body.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Method, StatusCode, Url};
+use sha2::Digest;
+
+use crate::req::Error;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl Algorithm {
+    fn parse(s: &str) -> Option<Algorithm> {
+        use Algorithm::*;
+        match s.to_ascii_uppercase().as_str() {
+            "MD5" => Some(Md5),
+            "MD5-SESS" => Some(Md5Sess),
+            "SHA-256" => Some(Sha256),
+            "SHA-256-SESS" => Some(Sha256Sess),
+            _ => None,
+        }
+    }
+    fn as_str(self) -> &'static str {
+        use Algorithm::*;
+        match self {
+            Md5 => "MD5",
+            Md5Sess => "MD5-sess",
+            Sha256 => "SHA-256",
+            Sha256Sess => "SHA-256-sess",
+        }
+    }
+    fn hash(self, data: &str) -> String {
+        use Algorithm::*;
+        match self {
+            Md5 | Md5Sess => format!("{:x}", md5::Md5::digest(data.as_bytes())),
+            Sha256 | Sha256Sess => format!("{:x}", sha2::Sha256::digest(data.as_bytes())),
+        }
+    }
+}
+
+/// A challenge received from a server, along with the number of times we
+/// have used its nonce.
+struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    qop_auth: bool,
+    stale: bool,
+    nc: u32,
+}
+
+impl Challenge {
+    /// Find and parse a `Digest` challenge that we are able to answer.
+    fn from_headers(headers: &HeaderMap) -> Option<Challenge> {
+        headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(Challenge::parse)
+    }
+    fn parse(header: &str) -> Option<Challenge> {
+        let header = header.trim_start();
+        let (scheme, rest) = header.split_at(header.find(' ')?);
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+        let params = parse_params(rest);
+        let algorithm = match params.get("algorithm") {
+            Some(a) => Algorithm::parse(a)?,
+            None => Algorithm::Md5,
+        };
+        let qop_auth = match params.get("qop") {
+            Some(qop) => {
+                if qop
+                    .split(',')
+                    .any(|q| q.trim().eq_ignore_ascii_case("auth"))
+                {
+                    true
+                } else {
+                    // only auth-int is offered
+                    return None;
+                }
+            }
+            None => false,
+        };
+        Some(Challenge {
+            realm: params.get("realm")?.clone(),
+            nonce: params.get("nonce")?.clone(),
+            opaque: params.get("opaque").cloned(),
+            algorithm,
+            qop_auth,
+            stale: params
+                .get("stale")
+                .map(|s| s.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            nc: 0,
+        })
+    }
+    fn authorization(&mut self, creds: &DigestAuth, method: &Method, url: &Url) -> HeaderValue {
+        self.nc += 1;
+        let uri = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_string(),
+        };
+        let alg = self.algorithm;
+        let cnonce = format!("{:016x}", rand::random::<u64>());
+        let nc = format!("{:08x}", self.nc);
+        let mut ha1 = alg.hash(&format!(
+            "{}:{}:{}",
+            creds.username, self.realm, creds.password
+        ));
+        if alg == Algorithm::Md5Sess || alg == Algorithm::Sha256Sess {
+            ha1 = alg.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = alg.hash(&format!("{}:{}", method.as_str(), uri));
+        let response = if self.qop_auth {
+            alg.hash(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, self.nonce, nc, cnonce, ha2
+            ))
+        } else {
+            alg.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            quote(&creds.username),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(&uri),
+            alg.as_str(),
+            response
+        );
+        if self.qop_auth {
+            header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+        }
+        if let Some(opaque) = &self.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+        }
+        // the username is validated when decoding the options, the challenge
+        // parameters came from a header and everything else is ascii
+        HeaderValue::from_str(&header).unwrap()
+    }
+}
+
+/// Parse the comma-separated `key=value` / `key="quoted value"` list following
+/// the scheme name.
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while matches!(chars.peek(), Some(c) if *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let key: String = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .to_ascii_lowercase();
+        if key.is_empty() {
+            return params;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+            value = value.trim().to_string();
+        }
+        params.insert(key, value);
+    }
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The most recent challenge for each origin, shared by all requests made with
+/// a client so that subsequent requests can authenticate pre-emptively.
+#[derive(Default)]
+pub struct NonceCache(Mutex<HashMap<String, Challenge>>);
+
+pub struct DigestAuth {
+    pub username: String,
+    pub password: String,
+    pub nonces: Arc<NonceCache>,
+}
+
+impl DigestAuth {
+    /// Send a request, answering a single 401 challenge if necessary. The
+    /// response to the authenticated request (or the original response if it
+    /// was not a challenge we can answer) is returned.
+    pub async fn send(
+        &self,
+        client: &reqwest::Client,
+        mut request: reqwest::Request,
+    ) -> Result<reqwest::Response, Error> {
+        let origin = request.url().origin().ascii_serialization();
+        // Requests with streamed bodies are rejected before we get here
+        let mut retry = request.try_clone().unwrap();
+        let used_nonce = {
+            let mut nonces = self.nonces.0.lock().unwrap();
+            nonces.get_mut(&origin).map(|challenge| {
+                let auth = challenge.authorization(self, request.method(), request.url());
+                request.headers_mut().insert(AUTHORIZATION, auth);
+                challenge.nonce.clone()
+            })
+        };
+        let resp = client.execute(request).await?;
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+        let mut challenge = match Challenge::from_headers(resp.headers()) {
+            Some(challenge) => challenge,
+            None => return Ok(resp),
+        };
+        if used_nonce.as_ref() == Some(&challenge.nonce) && !challenge.stale {
+            // we already answered this nonce, so the credentials are wrong
+            return Ok(resp);
+        }
+        let auth = challenge.authorization(self, retry.method(), retry.url());
+        retry.headers_mut().insert(AUTHORIZATION, auth);
+        self.nonces.0.lock().unwrap().insert(origin, challenge);
+        Ok(client.execute(retry).await?)
+    }
+}
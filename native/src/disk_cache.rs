@@ -0,0 +1,249 @@
+//! On-disk storage for the HTTP cache. Each response is stored in its own file
+//! in the cache directory, consisting of the length of the metadata (4 bytes,
+//! big endian), the metadata as JSON, and then the body. Files are written
+//! under a temporary name and renamed once complete, so a crash never leaves a
+//! partially written entry behind. The modification time records when the
+//! entry was last used, so that LRU eviction carries over between restarts.
+//! Entries are read and written with `tokio::fs` while requests are running;
+//! only `load` (when the client is made) uses blocking calls.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::runtime::Handle;
+use tokio::task;
+
+const ENTRY_EXT: &str = "entry";
+const TMP_EXT: &str = "tmp";
+
+/// Everything about a stored response except the body
+#[derive(Clone)]
+pub struct Meta {
+    pub status: u16,
+    pub headers: HeaderMap,
+    /// The request's values of the headers named by `Vary`
+    pub vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    pub response_time: SystemTime,
+    pub initial_age: Duration,
+    pub lifetime: Duration,
+}
+
+/// An entry found when scanning the cache directory
+pub struct Loaded {
+    pub key: String,
+    pub meta: Meta,
+    pub path: PathBuf,
+    pub body_len: usize,
+    pub last_used: SystemTime,
+}
+
+/// A file being written, which is removed unless it is committed
+pub struct TmpFile {
+    file: BufWriter<File>,
+    path: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+/// The name of the file for a key and the request's values of the headers
+/// named by `Vary`, so that each variant has its own file
+pub fn path(dir: &Path, key: &str, vary: &[(HeaderName, Vec<HeaderValue>)]) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    for (name, values) in vary {
+        hasher.update(b"\n");
+        hasher.update(name.as_str().as_bytes());
+        for value in values {
+            hasher.update(b"\0");
+            hasher.update(value.as_bytes());
+        }
+    }
+    let name = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    dir.join(name).with_extension(ENTRY_EXT)
+}
+
+/// Read all entries in `dir`, creating it if needed. Temporary files left
+/// behind by a crash and files which can't be parsed are removed.
+pub fn load(dir: &Path) -> io::Result<Vec<Loaded>> {
+    fs::create_dir_all(dir)?;
+    let mut loaded = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ENTRY_EXT) => match read_header(&path) {
+                Ok((key, meta, body_len, last_used)) => loaded.push(Loaded {
+                    key,
+                    meta,
+                    path,
+                    body_len,
+                    last_used,
+                }),
+                Err(_) => remove(&path),
+            },
+            Some(TMP_EXT) => remove(&path),
+            _ => (),
+        }
+    }
+    Ok(loaded)
+}
+
+/// Read the body of an entry, and mark it as used
+pub async fn read_body(path: &Path) -> io::Result<Bytes> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+    let mut len = [0; 4];
+    file.read_exact(&mut len).await?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).await?;
+    let meta_len = u32::from_be_bytes(len) as usize;
+    if meta_len > data.len() {
+        return Err(invalid("truncated entry"));
+    }
+    // `tokio::fs` has no way to do this
+    let file = file.into_std().await;
+    task::spawn_blocking(move || file.set_modified(SystemTime::now())).await??;
+    Ok(Bytes::from(data).slice(meta_len..))
+}
+
+/// Replace the metadata of an entry, keeping the body, which is returned
+pub async fn rewrite(path: &Path, key: &str, meta: &Meta) -> io::Result<Bytes> {
+    let body = read_body(path).await?;
+    let mut tmp = TmpFile::create(path, key, meta).await?;
+    tmp.write(&body).await?;
+    tmp.commit().await?;
+    Ok(body)
+}
+
+/// Remove a file, ignoring errors since it may already be gone. On the runtime
+/// this happens in the background, since it is called with the store locked.
+pub fn remove(path: &Path) {
+    let path = path.to_owned();
+    match Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn_blocking(move || fs::remove_file(path));
+        }
+        Err(_) => {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl TmpFile {
+    /// Start writing the entry which will be stored at `dest`
+    pub async fn create(dest: &Path, key: &str, meta: &Meta) -> io::Result<TmpFile> {
+        let path = dest.with_extension(format!("{:016x}.{}", rand::random::<u64>(), TMP_EXT));
+        let mut tmp = TmpFile {
+            file: BufWriter::new(File::create(&path).await?),
+            path,
+            dest: dest.to_owned(),
+            committed: false,
+        };
+        let meta = encode_meta(key, meta).to_string();
+        tmp.write(&(meta.len() as u32).to_be_bytes()).await?;
+        tmp.write(meta.as_bytes()).await?;
+        Ok(tmp)
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await
+    }
+
+    /// Make sure the data has reached the disk before moving the file into
+    /// place
+    pub async fn commit(mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+        tokio::fs::rename(&self.path, &self.dest).await?;
+        self.committed = true;
+        if let Some(dir) = self.dest.parent() {
+            File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            remove(&self.path);
+        }
+    }
+}
+
+fn read_header(path: &Path) -> io::Result<(String, Meta, usize, SystemTime)> {
+    let mut file = fs::File::open(path)?;
+    let file_meta = file.metadata()?;
+    let mut len = [0; 4];
+    file.read_exact(&mut len)?;
+    let meta_len = u32::from_be_bytes(len) as usize;
+    let body_len = (file_meta.len() as usize)
+        .checked_sub(len.len() + meta_len)
+        .ok_or_else(|| invalid("truncated entry"))?;
+    let mut meta = vec![0; meta_len];
+    file.read_exact(&mut meta)?;
+    let meta = serde_json::from_slice(&meta).map_err(|_| invalid("invalid metadata"))?;
+    let (key, meta) = decode_meta(&meta).ok_or_else(|| invalid("invalid metadata"))?;
+    Ok((key, meta, body_len, file_meta.modified()?))
+}
+
+fn encode_meta(key: &str, meta: &Meta) -> Value {
+    let header = |name: &HeaderName, value: &HeaderValue| {
+        json!([name.as_str(), base64::encode(value.as_bytes())])
+    };
+    json!({
+        "key": key,
+        "status": meta.status,
+        "headers": meta.headers.iter().map(|(k, v)| header(k, v)).collect::<Vec<_>>(),
+        "vary": meta.vary.iter().map(|(name, values)| {
+            json!([name.as_str(), values.iter().map(|v| base64::encode(v.as_bytes())).collect::<Vec<_>>()])
+        }).collect::<Vec<_>>(),
+        "response_time": millis(meta.response_time.duration_since(UNIX_EPOCH).unwrap_or_default()),
+        "initial_age": millis(meta.initial_age),
+        "lifetime": millis(meta.lifetime),
+    })
+}
+
+fn decode_meta(meta: &Value) -> Option<(String, Meta)> {
+    let name = |v: &Value| HeaderName::from_bytes(v.as_str()?.as_bytes()).ok();
+    let value = |v: &Value| HeaderValue::from_bytes(&base64::decode(v.as_str()?).ok()?).ok();
+    let duration = |k: &str| meta[k].as_u64().map(Duration::from_millis);
+    let mut headers = HeaderMap::new();
+    for header in meta["headers"].as_array()? {
+        headers.append(name(&header[0])?, value(&header[1])?);
+    }
+    let mut vary = Vec::new();
+    for v in meta["vary"].as_array()? {
+        let values = v[1].as_array()?.iter().map(value).collect::<Option<_>>()?;
+        vary.push((name(&v[0])?, values));
+    }
+    Some((
+        meta["key"].as_str()?.to_owned(),
+        Meta {
+            status: meta["status"].as_u64()? as u16,
+            headers,
+            vary,
+            response_time: UNIX_EPOCH + duration("response_time")?,
+            initial_age: duration("initial_age")?,
+            lifetime: duration("lifetime")?,
+        },
+    ))
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
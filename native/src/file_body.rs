@@ -0,0 +1,395 @@
+//! Request and response bodies which are read from or written to a file
+//! (`body => {file, Path}` and `response_body => {file, Path}`), so that large
+//! uploads and downloads don't pass through an erlang process
+
+use std::cmp;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use futures::stream;
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use reqwest::StatusCode;
+use rustler::{Atom, Encoder, Env, MapIterator, NifResult, NifUnitEnum, Term};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::atoms;
+use crate::cache;
+use crate::progress::{self, Progress};
+use crate::req::{Error, ErrorCode, Transport};
+
+/// The size of the chunks a request body is read in
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+pub struct RequestFile {
+    path: String,
+    offset: u64,
+    length: Option<u64>,
+    /// Set by `open`
+    file: Option<File>,
+}
+
+/// A failure to read the file while the body is sent. It is found in the
+/// source of the `reqwest::Error` (see `read_error`), so that it can be
+/// reported with code `file`.
+#[derive(Debug)]
+struct ReadError(String);
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl StdError for ReadError {}
+
+impl RequestFile {
+    /// Decode `{file, Path}` or `{file, Path, Offset, Length}`. Returns `None`
+    /// for the other kinds of `body`.
+    pub fn decode(term: Term) -> NifResult<Option<RequestFile>> {
+        let (tag, path, offset, length) = match term.decode::<(Atom, String)>() {
+            Ok((tag, path)) => (tag, path, 0, None),
+            Err(_) => match term.decode::<(Atom, String, u64, u64)>() {
+                Ok((tag, path, offset, length)) => (tag, path, offset, Some(length)),
+                Err(_) => return Ok(None),
+            },
+        };
+        if tag != atoms::file() {
+            return Ok(None);
+        }
+        Ok(Some(RequestFile {
+            path,
+            offset,
+            length,
+            file: None,
+        }))
+    }
+
+    /// Open the file and check that the range is within it, so that these
+    /// errors are reported before the request is sent
+    pub async fn open(&mut self) -> Result<(), Error> {
+        let file_error =
+            |e: io::Error| Error::from_reason(ErrorCode::File, format!("{}: {}", self.path, e));
+        let mut file = File::open(&self.path).await.map_err(file_error)?;
+        let metadata = file.metadata().await.map_err(file_error)?;
+        // eg. a directory can be opened, but not read
+        if !metadata.is_file() {
+            return Err(Error::from_reason(
+                ErrorCode::File,
+                format!("{}: not a regular file", self.path),
+            ));
+        }
+        let length = match (metadata.len().checked_sub(self.offset), self.length) {
+            (Some(available), None) => available,
+            (Some(available), Some(length)) if length <= available => length,
+            _ => {
+                return Err(Error::from_reason(
+                    ErrorCode::File,
+                    format!("{}: range is beyond the end of the file", self.path),
+                ))
+            }
+        };
+        file.seek(SeekFrom::Start(self.offset))
+            .await
+            .map_err(file_error)?;
+        self.length = Some(length);
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// The body, which is read from the file (opened by `open`) while it is
+    /// sent, and its length
+    pub fn body(self, progress: Option<&Arc<Progress>>) -> (reqwest::Body, u64) {
+        let RequestFile {
+            path, length, file, ..
+        } = self;
+        let file = file.expect("the file is opened before the request is built");
+        let length = length.unwrap();
+        let chunks = stream::try_unfold((file, length), move |(mut file, remaining)| {
+            let path = path.clone();
+            async move {
+                if remaining == 0 {
+                    return Ok(None);
+                }
+                let n = cmp::min(remaining, READ_CHUNK_SIZE);
+                let mut buf = BytesMut::with_capacity(n as usize);
+                match (&mut file).take(n).read_buf(&mut buf).await {
+                    Ok(0) => {
+                        return Err(ReadError(format!("{}: the file was truncated", path)));
+                    }
+                    Ok(_) => (),
+                    Err(e) => return Err(ReadError(format!("{}: {}", path, e))),
+                }
+                let remaining = remaining - buf.len() as u64;
+                Ok(Some((buf.freeze(), (file, remaining))))
+            }
+        });
+        (progress::request_body(progress, chunks), length)
+    }
+}
+
+/// The error reading a file body which caused `e`, if that is what happened
+pub fn read_error(e: &reqwest::Error) -> Option<String> {
+    let mut source = e.source();
+    while let Some(e) = source {
+        if let Some(ReadError(reason)) = e.downcast_ref() {
+            return Some(reason.clone());
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// The number of attempts with `resume => true`
+const DEFAULT_RESUME_ATTEMPTS: u32 = 3;
+
+#[derive(NifUnitEnum, Clone, Copy, PartialEq)]
+enum FileMode {
+    Write,
+    Append,
+}
+
+pub struct ResponseFile {
+    pub path: String,
+    mode: FileMode,
+    /// Write at this position, without truncating the file
+    offset: Option<u64>,
+    /// Set if an interrupted download is resumed, including the first attempt
+    pub max_attempts: Option<u32>,
+}
+
+/// What is needed to request the rest of an interrupted download
+pub struct Resume<'a> {
+    pub transport: &'a Transport,
+    pub request: reqwest::Request,
+}
+
+/// How the body of a response to a resumed request relates to what has been
+/// written
+enum Resumed {
+    /// The rest of the body
+    Continue,
+    /// The server ignored the range, so the whole body is sent again
+    Restart,
+}
+
+enum CopyError {
+    Body(Error),
+    File(Error),
+}
+
+impl ResponseFile {
+    /// Decode `{file, Path}` or `{file, Path, Opts}`. Returns `None` for the
+    /// other kinds of `response_body`.
+    pub fn decode(term: Term) -> NifResult<Option<ResponseFile>> {
+        let (path, opts) = match term.decode::<(Atom, String)>() {
+            Ok((tag, path)) if tag == atoms::file() => (path, None),
+            _ => match term.decode::<(Atom, String, MapIterator)>() {
+                Ok((tag, path, opts)) if tag == atoms::file() => (path, Some(opts)),
+                _ => return Ok(None),
+            },
+        };
+        let mut mode = FileMode::Write;
+        let mut offset = None;
+        for (k, v) in opts.into_iter().flatten() {
+            let k: Atom = k.decode()?;
+            if k == atoms::mode() {
+                mode = v.decode()?;
+            } else if k == atoms::offset() {
+                offset = Some(v.decode()?);
+            } else {
+                return Err(rustler::Error::RaiseTerm(Box::new((atoms::bad_opt(), k))));
+            }
+        }
+        if offset.is_some() && mode == FileMode::Append {
+            return Err(rustler::Error::BadArg);
+        }
+        Ok(Some(ResponseFile {
+            path,
+            mode,
+            offset,
+            max_attempts: None,
+        }))
+    }
+
+    /// Decode the `resume` opt, returning the maximum number of attempts
+    pub fn decode_resume(env: Env, term: Term) -> NifResult<Option<u32>> {
+        if let Ok(enabled) = term.decode::<bool>() {
+            return Ok(Some(DEFAULT_RESUME_ATTEMPTS).filter(|_| enabled));
+        }
+        match term.map_get(atoms::max_attempts().encode(env)) {
+            Ok(v) => match v.decode()? {
+                0 => Err(rustler::Error::BadArg),
+                n => Ok(Some(n)),
+            },
+            Err(_) => Ok(Some(DEFAULT_RESUME_ATTEMPTS)),
+        }
+    }
+
+    /// Write the body of `res` (whose headers have been moved to `headers`) to
+    /// the file, returning the number of bytes written. With `resume`, a
+    /// failure to read the body is followed by a request for the rest of it.
+    /// With `offset`, only a partial response is written, since anything else
+    /// isn't the range which belongs there.
+    pub async fn write(
+        &self,
+        res: &mut reqwest::Response,
+        headers: &HeaderMap,
+        cache_writer: &mut Option<cache::Writer>,
+        resume: Option<Resume<'_>>,
+        progress: Option<&Arc<Progress>>,
+    ) -> Result<u64, Error> {
+        if let Some(offset) = self.offset {
+            if res.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(Error::from_reason(
+                    ErrorCode::Body,
+                    format!(
+                        "expected a partial response to write at offset {}, got {}",
+                        offset,
+                        res.status().as_u16()
+                    ),
+                ));
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(self.mode == FileMode::Append)
+            .truncate(self.mode == FileMode::Write && self.offset.is_none())
+            .open(&self.path)
+            .await
+            .map_err(|e| self.file_error(e))?;
+        // where this download starts, in case it has to start again
+        let start = file
+            .seek(self.offset.map_or(SeekFrom::End(0), SeekFrom::Start))
+            .await
+            .map_err(|e| self.file_error(e))?;
+        let resume = resume.filter(|_| res.status() == StatusCode::OK);
+        let validator = resume.as_ref().and_then(|_| validator(headers));
+        let mut attempts = 1;
+        let mut written = 0;
+        let mut resumed = None;
+        loop {
+            let current = match &mut resumed {
+                Some(resumed) => resumed,
+                None => &mut *res,
+            };
+            let mut e = match self
+                .copy(current, &mut file, cache_writer, progress, &mut written)
+                .await
+            {
+                Ok(()) => break,
+                Err(CopyError::Body(e)) => e,
+                // failing to write the file is not worth retrying
+                Err(CopyError::File(e)) => return Err(e),
+            };
+            resumed = Some(loop {
+                let resume = match (&resume, self.max_attempts) {
+                    (Some(resume), Some(max)) if attempts < max => resume,
+                    _ => return Err(e),
+                };
+                attempts += 1;
+                match resume.send(written, validator.as_ref()).await {
+                    Ok((resp, Resumed::Continue)) => break resp,
+                    Ok((resp, Resumed::Restart)) => {
+                        if self.offset.is_none() {
+                            file.set_len(start).await.map_err(|e| self.file_error(e))?;
+                        }
+                        file.seek(SeekFrom::Start(start))
+                            .await
+                            .map_err(|e| self.file_error(e))?;
+                        written = 0;
+                        // the cached body would be mixed up
+                        *cache_writer = None;
+                        break resp;
+                    }
+                    Err(next) => e = next,
+                }
+            });
+        }
+        file.flush().await.map_err(|e| self.file_error(e))?;
+        Ok(written)
+    }
+
+    /// Copy the body of `res` to the file
+    async fn copy(
+        &self,
+        res: &mut reqwest::Response,
+        file: &mut File,
+        cache_writer: &mut Option<cache::Writer>,
+        progress: Option<&Arc<Progress>>,
+        written: &mut u64,
+    ) -> Result<(), CopyError> {
+        while let Some(chunk) = res.chunk().await.map_err(|e| CopyError::Body(e.into()))? {
+            progress::chunk_received(progress, &chunk);
+            if let Some(writer) = cache_writer {
+                writer.write(&chunk).await;
+            }
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| CopyError::File(self.file_error(e)))?;
+            *written += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn file_error(&self, e: io::Error) -> Error {
+        Error::from_reason(ErrorCode::File, format!("{}: {}", self.path, e))
+    }
+}
+
+impl<'a> Resume<'a> {
+    /// Request the body from `offset` onwards. Without a validator there is no
+    /// way to be sure that the resource hasn't changed, so the whole body is
+    /// requested again.
+    async fn send(
+        &self,
+        offset: u64,
+        validator: Option<&HeaderValue>,
+    ) -> Result<(reqwest::Response, Resumed), Error> {
+        // only `get` requests are resumed, so this can't fail
+        let mut request = self.request.try_clone().unwrap();
+        let headers = request.headers_mut();
+        // added by the cache, but they would turn a changed resource into a 304
+        headers.remove(IF_NONE_MATCH);
+        headers.remove(IF_MODIFIED_SINCE);
+        if let Some(validator) = validator {
+            let range = HeaderValue::from_str(&format!("bytes={}-", offset))
+                .expect("a number is a valid header value");
+            headers.insert(RANGE, range);
+            headers.insert(IF_RANGE, validator.clone());
+        }
+        let resp = self.transport.send(request).await?;
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT if content_range_start(resp.headers()) == Some(offset) => {
+                Ok((resp, Resumed::Continue))
+            }
+            StatusCode::OK => Ok((resp, Resumed::Restart)),
+            status => Err(Error::from_reason(
+                ErrorCode::Body,
+                format!("unexpected response when resuming: {}", status),
+            )),
+        }
+    }
+}
+
+/// The header to send as `If-Range`. Weak ETags can't be used for ranges.
+fn validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    match headers.get(ETAG) {
+        Some(etag) if !etag.as_bytes().starts_with(b"W/") => Some(etag.clone()),
+        _ => headers.get(LAST_MODIFIED).cloned(),
+    }
+}
+
+/// Parse the start of `Content-Range: bytes Start-End/Length`
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
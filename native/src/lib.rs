@@ -1,50 +1,130 @@
 use rustler::{nif, Env, NifResult, NifUnitEnum, Term};
 
+mod cache;
+mod circuit;
 mod client;
+mod coalesce;
+mod digest;
+mod disk_cache;
+mod file_body;
+mod limit;
+mod netrc;
+mod oauth2;
+mod progress;
+mod rate_limit;
 mod req;
+mod resource_binary;
+mod retry;
 mod runtime;
+mod sign;
+mod sigv4;
 mod utils;
 
 mod atoms {
     rustler::atoms! {
+        access_key,
+        active,
         additional_root_certs,
+        algorithm,
+        attempts,
+        aws_sigv4,
+        backoff,
         bad_opt,
         basic_auth,
         body,
+        burst,
+        cache,
         cancel,
         cancelled,
         chunk,
+        circuit_breaker,
         client_builder_error,
+        client_id,
+        client_secret,
+        coalesce,
+        concurrency,
         connect_timeout,
         cookie_store,
         danger_accept_invalid_certs,
         danger_accept_invalid_hostnames,
+        delay,
+        digest_auth,
+        dir,
+        duplex,
+        encoding,
+        erqwest_progress,
         erqwest_response,
         erqwest_runtime_stopped,
         error,
+        failure_threshold,
+        file,
         fin,
         follow_redirects,
         gzip,
+        half_open_requests,
+        header,
         headers,
+        hedge,
+        hedged,
+        http2_prior_knowledge,
         https_only,
         identity,
+        jitter,
+        key,
         length,
+        max_attempts,
+        max_bytes,
+        max_extra,
+        max_in_flight,
+        max_in_flight_per_host,
+        max_wait,
         method,
+        methods,
+        mode,
+        netrc,
         next,
+        oauth2,
+        offset,
         ok,
+        on_status,
+        open_duration,
+        passive,
         period,
         pool_idle_timeout,
         pool_max_idle_per_host,
+        progress,
         proxy,
+        queue_timeout,
+        rate,
+        rate_limit,
         reason,
+        received,
+        received_total,
+        region,
         reply,
         response_body,
+        resume,
+        retry,
+        scope,
+        secret_key,
+        send_window,
+        sent,
+        sent_total,
+        service,
+        session_token,
+        shared,
+        sign,
         status,
         stream,
         stream_response,
+        template,
         timeout,
+        timestamp_header,
+        token_url,
+        undefined,
         url,
         use_built_in_root_certs,
+        window,
     }
 }
 
@@ -52,6 +132,7 @@ fn load(env: Env, _info: Term) -> bool {
     rustler::resource!(client::ClientResource, env);
     rustler::resource!(req::ReqHandle, env);
     rustler::resource!(runtime::RuntimeResource, env);
+    rustler::resource!(resource_binary::BytesResource, env);
     true
 }
 
@@ -77,11 +158,16 @@ rustler::init!(
         runtime::stop_runtime,
         client::make_client,
         client::close_client,
+        client::circuit_state,
+        client::cache_purge,
         req::req,
+        req::req_many,
+        req::req_all,
         req::cancel,
         req::send,
         req::finish_send,
         req::read,
+        req::set_active,
         req::cancel_stream,
         feature
     ],
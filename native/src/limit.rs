@@ -0,0 +1,82 @@
+//! Limits on the number of requests a client has in flight, overall and per
+//! host. Requests over the limit are queued until a slot becomes free.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::Url;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::req::{Error, ErrorCode};
+use crate::utils::host_key;
+
+/// The most permits a tokio `Semaphore` can have (`Semaphore::MAX_PERMITS`,
+/// which isn't public in the version of tokio used here). `Semaphore::new`
+/// panics above this.
+pub const MAX_PERMITS: usize = usize::MAX >> 3;
+
+pub struct Limits {
+    client: Option<Arc<Semaphore>>,
+    per_host: Option<usize>,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+    queue_timeout: Option<Duration>,
+}
+
+/// Frees up the slot(s) when dropped
+pub struct Slot {
+    _client: Option<OwnedSemaphorePermit>,
+    _host: Option<OwnedSemaphorePermit>,
+}
+
+impl Limits {
+    pub fn new(
+        max_in_flight: Option<usize>,
+        max_in_flight_per_host: Option<usize>,
+        queue_timeout: Option<Duration>,
+    ) -> Limits {
+        Limits {
+            client: max_in_flight.map(|n| Arc::new(Semaphore::new(n))),
+            per_host: max_in_flight_per_host,
+            hosts: Mutex::new(HashMap::new()),
+            queue_timeout,
+        }
+    }
+
+    /// Wait for a slot for a request to `url`, for at most `queue_timeout`
+    pub async fn acquire(&self, url: &Url) -> Result<Slot, Error> {
+        let host = self.per_host.map(|n| {
+            let mut hosts = self.hosts.lock().unwrap();
+            // a host's semaphore is only referenced elsewhere by queued
+            // requests and by the permits of those in flight, so the ones
+            // referenced only from here are idle and can go
+            hosts.retain(|_, host| Arc::strong_count(host) > 1);
+            hosts
+                .entry(host_key(url))
+                .or_insert_with(|| Arc::new(Semaphore::new(n)))
+                .clone()
+        });
+        let acquire = async {
+            // the host slot is taken first so that requests queued for a busy
+            // host don't hold up requests to other hosts
+            let host = match host {
+                Some(host) => Some(host.acquire_owned().await.unwrap()),
+                None => None,
+            };
+            let client = match &self.client {
+                Some(client) => Some(client.clone().acquire_owned().await.unwrap()),
+                None => None,
+            };
+            Slot {
+                _client: client,
+                _host: host,
+            }
+        };
+        match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire).await.map_err(|_| {
+                Error::from_reason(ErrorCode::QueueTimeout, "timed out waiting for a free slot")
+            }),
+            None => Ok(acquire.await),
+        }
+    }
+}
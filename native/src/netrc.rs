@@ -0,0 +1,86 @@
+//! Parser for `.netrc` files, as used by curl and ftp.
+
+use std::path::{Path, PathBuf};
+
+struct Entry {
+    /// `None` for the `default` entry
+    machine: Option<String>,
+    login: String,
+    password: String,
+}
+
+pub struct Netrc {
+    entries: Vec<Entry>,
+}
+
+impl Netrc {
+    /// `$NETRC`, falling back to `~/.netrc`
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("NETRC")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".netrc")))
+    }
+
+    pub fn read(path: &Path) -> std::io::Result<Netrc> {
+        Ok(Netrc::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(contents: &str) -> Netrc {
+        let mut entries = Vec::new();
+        let mut current: Option<Entry> = None;
+        let mut lines = contents.lines();
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" => {
+                        entries.extend(current.take());
+                        current = Some(Entry {
+                            machine: if token == "machine" {
+                                tokens.next().map(String::from)
+                            } else {
+                                None
+                            },
+                            login: String::new(),
+                            password: String::new(),
+                        });
+                    }
+                    "login" | "password" => {
+                        if let (Some(entry), Some(value)) = (current.as_mut(), tokens.next()) {
+                            if token == "login" {
+                                entry.login = value.to_string();
+                            } else {
+                                entry.password = value.to_string();
+                            }
+                        }
+                    }
+                    "account" => {
+                        tokens.next();
+                    }
+                    "macdef" => {
+                        // a macro definition continues until an empty line
+                        for line in lines.by_ref() {
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        break;
+                    }
+                    _ if token.starts_with('#') => break,
+                    _ => (),
+                }
+            }
+        }
+        entries.extend(current);
+        Netrc { entries }
+    }
+
+    /// The `(login, password)` for `host`, or from the `default` entry.
+    pub fn lookup(&self, host: &str) -> Option<(&str, &str)> {
+        self.entries
+            .iter()
+            .find(|e| e.machine.as_deref() == Some(host))
+            .or_else(|| self.entries.iter().find(|e| e.machine.is_none()))
+            .map(|e| (e.login.as_str(), e.password.as_str()))
+    }
+}
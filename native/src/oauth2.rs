@@ -0,0 +1,172 @@
+//! OAuth2 client credentials grant (RFC 6749 section 4.4). A token is fetched
+//! by the first request that needs one and shared by all requests made with the
+//! client. Once most of its lifetime has passed, the next request triggers a
+//! refresh in the background while continuing to use the current token.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::StatusCode;
+use tokio::sync::RwLock;
+
+use crate::req::{Error, ErrorCode};
+
+pub struct Config {
+    pub token_url: reqwest::Url,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+}
+
+struct Token {
+    header: HeaderValue,
+    /// `None` if the server didn't specify a lifetime
+    refresh_at: Option<Instant>,
+    expires_at: Option<Instant>,
+}
+
+pub struct OAuth2 {
+    config: Config,
+    token: RwLock<Option<Token>>,
+    refreshing: AtomicBool,
+}
+
+impl OAuth2 {
+    pub fn new(config: Config) -> OAuth2 {
+        OAuth2 {
+            config,
+            token: RwLock::new(None),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+
+    /// Send a request with a bearer token, fetching a new token and retrying
+    /// once if the server responds with 401. Requests that already have an
+    /// `Authorization` header are sent as they are.
+    pub async fn send(
+        self: &Arc<Self>,
+        client: &reqwest::Client,
+        mut request: reqwest::Request,
+    ) -> Result<reqwest::Response, Error> {
+        if !self.authorize(client, &mut request).await? {
+            return Ok(client.execute(request).await?);
+        }
+        // Requests with streamed bodies are not retried, see `Req::run`
+        let retry = request.try_clone();
+        let resp = client.execute(request).await?;
+        match retry {
+            Some(mut retry) if resp.status() == StatusCode::UNAUTHORIZED => {
+                let rejected = retry.headers_mut().remove(AUTHORIZATION);
+                let header = self.refresh(client, rejected.as_ref()).await?;
+                retry.headers_mut().insert(AUTHORIZATION, header);
+                Ok(client.execute(retry).await?)
+            }
+            _ => Ok(resp),
+        }
+    }
+
+    /// Add a bearer token unless the request already has an `Authorization`
+    /// header. Returns whether a token was added.
+    pub async fn authorize(
+        self: &Arc<Self>,
+        client: &reqwest::Client,
+        request: &mut reqwest::Request,
+    ) -> Result<bool, Error> {
+        if request.headers().contains_key(AUTHORIZATION) {
+            return Ok(false);
+        }
+        let header = self.bearer(client).await?;
+        request.headers_mut().insert(AUTHORIZATION, header);
+        Ok(true)
+    }
+
+    async fn bearer(self: &Arc<Self>, client: &reqwest::Client) -> Result<HeaderValue, Error> {
+        if let Some(token) = &*self.token.read().await {
+            let now = Instant::now();
+            if token.expires_at.is_none_or(|t| now < t) {
+                if token.refresh_at.is_some_and(|t| now >= t)
+                    && !self.refreshing.swap(true, Ordering::Relaxed)
+                {
+                    let this = self.clone();
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        // Fetch without holding the lock so that requests can
+                        // keep using the current token in the meantime. If this
+                        // fails, the token will be fetched again once it has
+                        // expired.
+                        if let Ok(token) = this.fetch(&client).await {
+                            *this.token.write().await = Some(token);
+                        }
+                        this.refreshing.store(false, Ordering::Relaxed);
+                    });
+                }
+                return Ok(token.header.clone());
+            }
+        }
+        self.refresh(client, None).await
+    }
+
+    /// Fetch a new token, unless another request did so while we were waiting
+    /// for the lock. `rejected` is a token the server refused, which must not
+    /// be reused even if it hasn't expired.
+    async fn refresh(
+        &self,
+        client: &reqwest::Client,
+        rejected: Option<&HeaderValue>,
+    ) -> Result<HeaderValue, Error> {
+        let mut token = self.token.write().await;
+        if let Some(token) = &*token {
+            if Some(&token.header) != rejected
+                && token.expires_at.is_none_or(|t| Instant::now() < t)
+            {
+                return Ok(token.header.clone());
+            }
+        }
+        let new_token = self.fetch(client).await?;
+        let header = new_token.header.clone();
+        *token = Some(new_token);
+        Ok(header)
+    }
+
+    async fn fetch(&self, client: &reqwest::Client) -> Result<Token, Error> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.config.scope {
+            form.push(("scope", scope));
+        }
+        // lifetimes are measured from before the request was sent
+        let now = Instant::now();
+        let resp = client
+            .post(self.config.token_url.clone())
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&form)
+            .send()
+            .await?;
+        let status = resp.status();
+        let body = resp.bytes().await?;
+        if !status.is_success() {
+            return Err(Error::from_reason(
+                ErrorCode::Oauth2,
+                format!(
+                    "token request failed with status {}: {}",
+                    status.as_u16(),
+                    String::from_utf8_lossy(&body)
+                ),
+            ));
+        }
+        let json: serde_json::Value =
+            serde_json::from_slice(&body).map_err(|e| Error::from_reason(ErrorCode::Oauth2, e))?;
+        let access_token = json["access_token"]
+            .as_str()
+            .ok_or_else(|| Error::from_reason(ErrorCode::Oauth2, "no access_token in response"))?;
+        let header = HeaderValue::from_str(&format!("Bearer {}", access_token))
+            .map_err(|e| Error::from_reason(ErrorCode::Oauth2, e))?;
+        let lifetime = json["expires_in"].as_u64().map(Duration::from_secs);
+        Ok(Token {
+            header,
+            refresh_at: lifetime.map(|l| now + l * 3 / 4),
+            expires_at: lifetime.map(|l| now + l),
+        })
+    }
+}
@@ -0,0 +1,156 @@
+//! Periodic reports of how much of a request and its response has been
+//! transferred (the `progress` opt)
+
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{TryStream, TryStreamExt};
+use rustler::types::map;
+use rustler::{Encoder, Env, LocalPid, NifResult, OwnedEnv, Term, TermType};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::atoms;
+
+/// Stands in for a total which isn't known
+const UNKNOWN: u64 = u64::MAX;
+
+/// The counters are updated by the bodies as they are transferred, and read by
+/// `report`, which runs alongside the request.
+pub struct Progress {
+    pid: LocalPid,
+    interval: Duration,
+    sent: AtomicU64,
+    sent_total: AtomicU64,
+    received: AtomicU64,
+    received_total: AtomicU64,
+}
+
+impl Progress {
+    /// Decode `{Pid, IntervalMs}` or `{Pid, Ref, IntervalMs}`, returning `Ref`
+    /// if given, to be used in the reports instead of the request's own ref
+    pub fn decode(term: Term) -> NifResult<(Arc<Progress>, Option<Term>)> {
+        let (pid, ref_, interval) = match term.decode::<(LocalPid, u64)>() {
+            Ok((pid, interval)) => (pid, None, interval),
+            Err(_) => {
+                let (pid, ref_, interval): (LocalPid, Term, u64) = term.decode()?;
+                if ref_.get_type() != TermType::Ref {
+                    return Err(rustler::Error::BadArg);
+                }
+                (pid, Some(ref_), interval)
+            }
+        };
+        if interval == 0 {
+            return Err(rustler::Error::BadArg);
+        }
+        let progress = Arc::new(Progress {
+            pid,
+            interval: Duration::from_millis(interval),
+            sent: AtomicU64::new(0),
+            sent_total: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            received_total: AtomicU64::new(UNKNOWN),
+        });
+        Ok((progress, ref_))
+    }
+
+    pub fn set_sent_total(&self, total: Option<u64>) {
+        self.sent_total
+            .store(total.unwrap_or(UNKNOWN), Ordering::Relaxed);
+    }
+
+    /// A request body which isn't streamed has been sent once the response
+    /// arrives
+    pub fn body_sent(&self) {
+        let total = self.sent_total.load(Ordering::Relaxed);
+        if total != UNKNOWN {
+            self.sent.store(total, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_received_total(&self, total: Option<u64>) {
+        self.received_total
+            .store(total.unwrap_or(UNKNOWN), Ordering::Relaxed);
+    }
+
+    /// Send a report every `interval`. This never returns, so it is dropped
+    /// along with the request.
+    pub async fn report<T: Encoder>(self: Arc<Self>, caller_ref: T) {
+        let mut interval = time::interval_at(Instant::now() + self.interval, self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut env = OwnedEnv::new();
+        loop {
+            interval.tick().await;
+            env.send_and_clear(&self.pid, |env| {
+                (atoms::erqwest_progress(), &caller_ref, self.encode(env)).encode(env)
+            });
+        }
+    }
+
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let count = |counter: &AtomicU64| match counter.load(Ordering::Relaxed) {
+            UNKNOWN => atoms::undefined().encode(env),
+            n => n.encode(env),
+        };
+        let mut map = map::map_new(env);
+        for (k, counter) in [
+            (atoms::sent(), &self.sent),
+            (atoms::sent_total(), &self.sent_total),
+            (atoms::received(), &self.received),
+            (atoms::received_total(), &self.received_total),
+        ] {
+            map = map.map_put(k.encode(env), count(counter)).unwrap();
+        }
+        map
+    }
+}
+
+/// A streamed request body, counting the bytes as they are handed to the
+/// connection
+pub fn request_body<S>(progress: Option<&Arc<Progress>>, chunks: S) -> reqwest::Body
+where
+    S: TryStream<Ok = Bytes> + Send + Sync + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    match progress {
+        Some(progress) => {
+            let progress = progress.clone();
+            reqwest::Body::wrap_stream(chunks.inspect_ok(move |chunk| {
+                progress
+                    .sent
+                    .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }))
+        }
+        None => reqwest::Body::wrap_stream(chunks),
+    }
+}
+
+/// Count a chunk of the response body which has been read
+pub fn chunk_received(progress: Option<&Arc<Progress>>, chunk: &[u8]) {
+    if let Some(progress) = progress {
+        progress
+            .received
+            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Read the whole response body, a chunk at a time if it is to be counted
+pub async fn response_body(
+    progress: Option<&Arc<Progress>>,
+    mut res: reqwest::Response,
+) -> reqwest::Result<Bytes> {
+    if progress.is_none() {
+        return res.bytes().await;
+    }
+    let mut chunks = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        chunk_received(progress, &chunk);
+        chunks.push(chunk);
+    }
+    Ok(match chunks.len() {
+        1 => chunks.pop().unwrap(),
+        _ => chunks.concat().into(),
+    })
+}
@@ -0,0 +1,119 @@
+//! Token bucket rate limiting, either for the whole client or per host.
+//! Requests over the limit are delayed until a token is available, for at most
+//! `max_wait`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use rustler::{Atom, Encoder, Env, NifResult, NifUnitEnum, Term};
+
+use crate::atoms;
+use crate::req::{Error, ErrorCode};
+use crate::utils::{host_key, maybe_timeout};
+
+#[derive(NifUnitEnum, PartialEq)]
+enum Scope {
+    Client,
+    Host,
+}
+
+struct Bucket {
+    /// Negative if requests are waiting for tokens
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimit {
+    /// Tokens per second
+    rate: f64,
+    burst: f64,
+    max_wait: Option<Duration>,
+    scope: Scope,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimit {
+    pub fn decode(env: Env, term: Term) -> NifResult<RateLimit> {
+        let get = |k: Atom| term.map_get(k.encode(env)).ok();
+        let number = |t: Term| {
+            t.decode::<f64>()
+                .or_else(|_| t.decode::<u64>().map(|n| n as f64))
+        };
+        let rate = number(term.map_get(atoms::rate().encode(env))?)?;
+        let burst = match get(atoms::burst()) {
+            Some(v) => number(v)?,
+            None => rate.ceil(),
+        };
+        if rate <= 0.0 || burst < 1.0 {
+            return Err(rustler::Error::BadArg);
+        }
+        Ok(RateLimit {
+            rate,
+            burst,
+            max_wait: match get(atoms::max_wait()) {
+                Some(v) => maybe_timeout(v)?,
+                None => None,
+            },
+            scope: match get(atoms::scope()) {
+                Some(v) => v.decode()?,
+                None => Scope::Client,
+            },
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Wait until a request to `url` may be sent
+    pub async fn wait(&self, url: &Url) -> Result<(), Error> {
+        let key = match self.scope {
+            Scope::Client => String::new(),
+            Scope::Host => host_key(url),
+        };
+        let delay = {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap();
+            if !buckets.contains_key(&key) {
+                // a bucket which has refilled is no different from a new one,
+                // so those are dropped rather than kept for every host
+                buckets.retain(|_, b| self.refill(b, now) < self.burst);
+            }
+            let bucket = buckets.entry(key).or_insert(Bucket {
+                tokens: self.burst,
+                updated: now,
+            });
+            bucket.tokens = self.refill(bucket, now).min(self.burst);
+            bucket.updated = now;
+            // take the token now, even if it only becomes available later, so
+            // that waiting requests are served in order
+            let delay =
+                match Duration::try_from_secs_f64((1.0 - bucket.tokens).max(0.0) / self.rate) {
+                    Ok(delay) if self.max_wait.is_none_or(|max| delay <= max) => delay,
+                    Ok(delay) => {
+                        return Err(Error::from_reason(
+                            ErrorCode::RateLimited,
+                            format!("rate limited for {} ms", delay.as_millis()),
+                        ))
+                    }
+                    // with a tiny rate, the delay is too long to wait for at all
+                    Err(_) => {
+                        return Err(Error::from_reason(
+                            ErrorCode::RateLimited,
+                            "rate limited indefinitely",
+                        ))
+                    }
+                };
+            bucket.tokens -= 1.0;
+            delay
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+
+    /// The tokens in `bucket` at `now`, before capping them at `burst`
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate
+    }
+}
@@ -1,13 +1,15 @@
 use futures::channel::mpsc::{self, Receiver, Sender, UnboundedReceiver};
-use futures::future::{AbortHandle, Abortable, OptionFuture};
-use futures::{Future, SinkExt, StreamExt};
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use futures::future::{poll_fn, AbortHandle, Abortable, OptionFuture};
+use futures::stream::FuturesUnordered;
+use futures::{Future, StreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH};
 use rustler::env::SavedTerm;
 use rustler::types::binary::NewBinary;
 use rustler::types::map;
 use rustler::{Atom, Binary, Encoder, Env, ListIterator, LocalPid, NifResult, Term};
 use rustler::{MapIterator, NifMap, NifUnitEnum, OwnedEnv, ResourceArc};
 use std::borrow::BorrowMut;
+use std::collections::VecDeque;
 use std::convert::Infallible;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -16,15 +18,31 @@ use std::thread::{self, ThreadId};
 use std::time::Duration;
 use std::{mem, str};
 use bytes::Bytes;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
 
 use crate::atoms;
+use crate::cache::{self, Cache, CacheStatus, Lookup, Outcome};
+use crate::circuit::{CircuitBreaker, Permit};
 use crate::client::ClientResource;
-use crate::utils::maybe_timeout;
+use crate::coalesce::{Coalescer, KeyHeaders, Leader, Role};
+use crate::digest::DigestAuth;
+use crate::file_body::{self, RequestFile, ResponseFile, Resume};
+use crate::limit::Limits;
+use crate::netrc::Netrc;
+use crate::oauth2::OAuth2;
+use crate::progress::{self, Progress};
+use crate::rate_limit::RateLimit;
+use crate::resource_binary::{make_binary, make_binary_from_chunks};
+use crate::retry::RetryPolicy;
+use crate::sign::Sign;
+use crate::sigv4::{Payload, SigV4};
+use crate::utils::{iodata_to_bytes, iodata_to_chunks, maybe_limit, maybe_timeout, PinnedEnv};
 
 const DEFAULT_READ_LENGTH: usize = 8 * 1024 * 1024;
 
 #[derive(NifUnitEnum, Clone, Copy, Debug)]
-enum Method {
+pub enum Method {
     Options,
     Get,
     Post,
@@ -59,8 +77,16 @@ struct ReqBase {
     method: Method,
 }
 
-#[derive(NifUnitEnum, Debug)]
-enum ErrorCode {
+#[derive(NifMap)]
+struct SigV4Base {
+    access_key: String,
+    secret_key: String,
+    region: String,
+    service: String,
+}
+
+#[derive(NifUnitEnum, Clone, Debug)]
+pub enum ErrorCode {
     Cancelled,
     Url,
     Request,
@@ -68,17 +94,22 @@ enum ErrorCode {
     Connect,
     Timeout,
     Body,
+    Oauth2,
+    CircuitOpen,
+    QueueTimeout,
+    RateLimited,
+    File,
     Unknown,
 }
 
-#[derive(NifMap, Debug)]
-struct Error {
-    code: ErrorCode,
+#[derive(NifMap, Clone, Debug)]
+pub struct Error {
+    pub code: ErrorCode,
     reason: String,
 }
 
 impl Error {
-    fn from_reason(code: ErrorCode, reason: impl ToString) -> Error {
+    pub fn from_reason(code: ErrorCode, reason: impl ToString) -> Error {
         Error {
             code,
             reason: reason.to_string(),
@@ -89,6 +120,9 @@ impl Error {
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Error {
         use ErrorCode::*;
+        if let Some(reason) = file_body::read_error(&e) {
+            return Error::from_reason(File, reason);
+        }
         let code = if e.is_timeout() {
             Timeout
         } else if e.is_redirect() {
@@ -122,6 +156,12 @@ impl<'a> Into<CallerRef> for Term<'a> {
     }
 }
 
+impl CallerRef {
+    fn copy(&self) -> CallerRef {
+        OwnedEnv::new().run(|env| self.encode(env).into())
+    }
+}
+
 impl Encoder for CallerRef {
     fn encode<'a>(&self, dest: Env<'a>) -> Term<'a> {
         self.env.run(|env| self.ref_.load(env).in_env(dest))
@@ -140,16 +180,37 @@ struct ReadOpts {
     period: Option<Duration>,
 }
 
+/// Sent by `read` and `set_active` to the future streaming the response body
+enum StreamCmd {
+    Read(ReadOpts),
+    /// Send this many more chunks as they arrive, without waiting for `read`
+    SetActive(u32),
+}
+
 enum IsFin {
     Fin,
     NoFin,
 }
 
+#[derive(Clone)]
+enum RespBody {
+    Bytes(Bytes),
+    /// The path and the number of bytes written
+    File(String, u64),
+}
+
 /// Helper for storing/encoding an HTTP response
-struct Resp {
+#[derive(Clone)]
+pub struct Resp {
     status: u16,
     headers: HeaderMap<HeaderValue>,
-    body: Option<Bytes>,
+    body: Option<RespBody>,
+    /// Only reported if retries are enabled
+    attempts: Option<u32>,
+    /// Only reported if hedging is enabled
+    hedged: Option<bool>,
+    /// Only reported if the client has a cache
+    cache: Option<CacheStatus>,
 }
 
 impl Resp {
@@ -172,10 +233,27 @@ impl Resp {
         map = map
             .map_put(atoms::headers().encode(env), headers1.encode(env))
             .unwrap();
-        if let Some(bytes) = self.body {
-            let mut body = NewBinary::new(env, bytes.len());
-            body.as_mut_slice().copy_from_slice(&bytes);
-            map = map.map_put(atoms::body().encode(env), body.into()).unwrap();
+        if let Some(body) = self.body {
+            let body = match body {
+                RespBody::Bytes(bytes) => make_binary(env, bytes),
+                RespBody::File(path, written) => (atoms::file(), path, written).encode(env),
+            };
+            map = map.map_put(atoms::body().encode(env), body).unwrap();
+        }
+        if let Some(attempts) = self.attempts {
+            map = map
+                .map_put(atoms::attempts().encode(env), attempts.encode(env))
+                .unwrap();
+        }
+        if let Some(hedged) = self.hedged {
+            map = map
+                .map_put(atoms::hedged().encode(env), hedged.encode(env))
+                .unwrap();
+        }
+        if let Some(cache) = self.cache {
+            map = map
+                .map_put(atoms::cache().encode(env), cache.encode(env))
+                .unwrap();
         }
         map.encode(env)
     }
@@ -189,6 +267,19 @@ struct ReqData {
     method: Method,
     body: Option<ReqBody>,
     timeout: Option<Duration>,
+    digest_auth: Option<DigestAuth>,
+    aws_sigv4: Option<SigV4>,
+    sign: Option<Sign>,
+    oauth2: Option<Arc<OAuth2>>,
+    netrc: Option<Arc<Netrc>>,
+    retry: Option<RetryPolicy>,
+    circuit: Option<Arc<CircuitBreaker>>,
+    limits: Option<Arc<Limits>>,
+    rate_limit: Option<Arc<RateLimit>>,
+    hedge: Option<Hedge>,
+    coalesce: Option<(Arc<Coalescer<Coalesced>>, KeyHeaders)>,
+    cache: Option<Arc<Cache>>,
+    progress: Option<Arc<Progress>>,
 }
 
 impl ReqData {
@@ -201,33 +292,105 @@ impl ReqData {
             method,
             body,
             timeout,
+            digest_auth: _,
+            aws_sigv4,
+            sign,
+            oauth2: _,
+            netrc,
+            retry: _,
+            circuit: _,
+            limits: _,
+            rate_limit: _,
+            hedge: _,
+            coalesce: _,
+            cache: _,
+            progress,
         } = self;
+        // the env is kept alive by the request body if it shares its binaries
+        let env = PinnedEnv::new(env);
         // we use unwrap for the binaries we checked the types of before saving
         env.run(|e| {
             let bin = url.load(e).decode::<Binary>().unwrap();
             let s = str::from_utf8(&bin).map_err(|e| Error::from_reason(ErrorCode::Url, e))?;
             let url = reqwest::Url::parse(s).map_err(|e| Error::from_reason(ErrorCode::Url, e))?;
-            let mut builder = client.request(method.into(), url);
+            let method: reqwest::Method = method.into();
+            let mut header_map = HeaderMap::with_capacity(headers.len());
             for (k, v) in headers {
                 let k = HeaderName::from_bytes(&k.load(e).decode::<Binary>().unwrap())
                     .map_err(|e| Error::from_reason(ErrorCode::Request, e))?;
                 let v = HeaderValue::from_bytes(&v.load(e).decode::<Binary>().unwrap())
                     .map_err(|e| Error::from_reason(ErrorCode::Request, e))?;
-                builder = builder.header(k, v);
+                header_map.append(k, v);
+            }
+            let body = match body {
+                Some(ReqBody::Complete(iodata)) => {
+                    // we don't know if this is valid iodata()
+                    let iodata = iodata_to_bytes(&env, iodata.load(e)).ok_or_else(|| {
+                        Error::from_reason(ErrorCode::Request, "bad request body")
+                    })?;
+                    Some(Ok(iodata))
+                }
+                // streamed bodies, with a length if it is known
+                Some(ReqBody::Stream(rx)) => {
+                    Some(Err((progress::request_body(progress.as_ref(), rx), None)))
+                }
+                Some(ReqBody::File(file)) => {
+                    let (body, length) = file.body(progress.as_ref());
+                    Some(Err((body, Some(length))))
+                }
+                None => None,
+            };
+            if let Some(progress) = &progress {
+                progress.set_sent_total(match &body {
+                    Some(Ok(iodata)) => Some(iodata.len() as u64),
+                    Some(Err((_, length))) => *length,
+                    None => Some(0),
+                });
             }
+            if let Some(sign) = sign {
+                let body = match &body {
+                    Some(Ok(iodata)) => &iodata[..],
+                    Some(Err(_)) if sign.signs_body() => {
+                        return Err(Error::from_reason(
+                            ErrorCode::Request,
+                            "sign cannot include the body of a streamed request",
+                        ))
+                    }
+                    _ => &[],
+                };
+                sign.sign(&method, &url, &mut header_map, body);
+            }
+            if let Some(aws_sigv4) = aws_sigv4 {
+                let payload = match &body {
+                    Some(Ok(iodata)) => Payload::Bytes(&iodata[..]),
+                    Some(Err(_)) => Payload::Unsigned,
+                    None => Payload::Bytes(&[]),
+                };
+                aws_sigv4.sign(&method, &url, &mut header_map, payload);
+            }
+            if let Some(netrc) = netrc {
+                if !header_map.contains_key(AUTHORIZATION) {
+                    if let Some((login, password)) = url.host_str().and_then(|h| netrc.lookup(h)) {
+                        let credentials = base64::encode(format!("{}:{}", login, password));
+                        let mut value = HeaderValue::from_str(&format!("Basic {}", credentials))
+                            .expect("base64 is a valid header value");
+                        value.set_sensitive(true);
+                        header_map.insert(AUTHORIZATION, value);
+                    }
+                }
+            }
+            let mut builder = client.request(method, url).headers(header_map);
             if let Some(timeout) = timeout {
                 builder = builder.timeout(timeout);
             }
             match body {
-                Some(ReqBody::Complete(iodata)) => {
-                    // we don't know if this is valid iodata()
-                    let iodata = iodata
-                        .load(e)
-                        .decode_as_binary()
-                        .map_err(|_| Error::from_reason(ErrorCode::Request, "bad request body"))?;
-                    builder = builder.body(iodata.to_vec());
+                Some(Ok(iodata)) => builder = builder.body(iodata),
+                Some(Err((body, length))) => {
+                    if let Some(length) = length {
+                        builder = builder.header(CONTENT_LENGTH, length);
+                    }
+                    builder = builder.body(body);
                 }
-                Some(ReqBody::Stream(rx)) => builder = builder.body(reqwest::Body::wrap_stream(rx)),
                 None => (),
             }
             Ok(builder)
@@ -235,6 +398,118 @@ impl ReqData {
     }
 }
 
+/// The result that the leader of coalesced requests shares with the followers
+pub type Coalesced = Result<Resp, Error>;
+
+/// Send up to `max_extra` additional copies of a request, `delay` apart, and use
+/// whichever response arrives first.
+struct Hedge {
+    delay: Duration,
+    max_extra: u32,
+}
+
+/// Sends a single attempt of a request, taking care of authentication schemes
+/// that may need an extra round-trip.
+pub struct Transport {
+    client: reqwest::Client,
+    digest_auth: Option<DigestAuth>,
+    oauth2: Option<Arc<OAuth2>>,
+    circuit: Option<Arc<CircuitBreaker>>,
+    rate_limit: Option<Arc<RateLimit>>,
+}
+
+impl Transport {
+    /// Wait for the rate limit and check the circuit breaker before sending
+    /// each attempt.
+    async fn admit(&self, url: &reqwest::Url) -> Result<Option<Permit<'_>>, Error> {
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.wait(url).await?;
+        }
+        match &self.circuit {
+            Some(circuit) => Ok(Some(circuit.acquire(url)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn send(&self, request: reqwest::Request) -> Result<reqwest::Response, Error> {
+        let permit = self.admit(request.url()).await?;
+        let result = match (&self.digest_auth, &self.oauth2) {
+            (Some(digest_auth), _) => digest_auth.send(&self.client, request).await,
+            (None, Some(oauth2)) => oauth2.send(&self.client, request).await,
+            (None, None) => self.client.execute(request).await.map_err(Error::from),
+        };
+        if let Some(permit) = permit {
+            permit.record(result.as_ref());
+        }
+        result
+    }
+
+    /// Like `send`, but sends another copy of the request each time
+    /// `hedge.delay` passes without a response. Each copy runs as a task of its
+    /// own, and the ones which lose are aborted. Also returns whether one of
+    /// the extra copies won.
+    async fn send_hedged(
+        self: &Arc<Self>,
+        request: reqwest::Request,
+        hedge: &Hedge,
+    ) -> (Result<reqwest::Response, Error>, bool) {
+        // also aborts the copies if the request is cancelled
+        let mut copies = AbortOnDrop(Vec::new());
+        let mut attempt = |request, hedged| {
+            let (abort_handle, abort_registration) = AbortHandle::new_pair();
+            copies.0.push(abort_handle);
+            let transport = self.clone();
+            let task = tokio::spawn(Abortable::new(
+                async move { transport.send(request).await },
+                abort_registration,
+            ));
+            async move {
+                let result = match task.await {
+                    Ok(Ok(result)) => result,
+                    // nothing is aborted before this returns, so the task
+                    // must have panicked
+                    _ => Err(Error::from_reason(ErrorCode::Unknown, "hedged copy failed")),
+                };
+                (result, hedged)
+            }
+        };
+        // hedging is not allowed with a streamed body, so this can't fail
+        let template = request.try_clone();
+        let mut pending = FuturesUnordered::new();
+        pending.push(attempt(request, false));
+        let mut extra = 0;
+        let mut next_at = Instant::now() + hedge.delay;
+        loop {
+            let can_hedge = extra < hedge.max_extra && template.is_some();
+            tokio::select! {
+                Some((result, hedged)) = pending.next() => {
+                    // if a copy fails, wait for the others
+                    if result.is_ok() || pending.is_empty() {
+                        return (result, hedged);
+                    }
+                }
+                _ = tokio::time::sleep_until(next_at), if can_hedge => {
+                    let copy = template.as_ref().and_then(|r| r.try_clone()).unwrap();
+                    pending.push(attempt(copy, true));
+                    extra += 1;
+                    next_at += hedge.delay;
+                }
+            }
+        }
+    }
+}
+
+/// Aborts the futures when dropped
+struct AbortOnDrop(Vec<AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for abort_handle in &self.0 {
+            abort_handle.abort();
+        }
+    }
+}
+
 struct Req {
     caller_ref: Option<CallerRef>,
     caller_pid: LocalPid,
@@ -242,16 +517,25 @@ struct Req {
     /// An indicator for whether the future was dropped. This doesn't strictly
     /// need to be an atomic since we only access it from `initial_thread`.
     dropped_on_initial_thread: Arc<AtomicBool>,
-    /// The channels we use to feed the request body to `reqwest`: The other end
-    /// of the `Sender` is converted to a Stream and given to `reqwest`. We get
-    /// new data from erlang on the receiver and feed it to the sender. This
-    /// allows us to provide backpressure by replying to erlang after each chunk
-    /// is successfully `fed`.
+    /// The channels we use to feed the request body to `reqwest`, see `Upload`
     req_body_channels: Option<(
-        Sender<Result<Vec<u8>, Infallible>>,
+        Sender<Result<Bytes, Infallible>>,
         UnboundedReceiver<SendCmd>,
     )>,
-    resp_stream_rx: Option<UnboundedReceiver<ReadOpts>>,
+    resp_stream_rx: Option<UnboundedReceiver<StreamCmd>>,
+    /// The number of bytes of the request body which can be queued before
+    /// `next` is held back
+    send_window: Option<usize>,
+    /// Keep streaming the request body after the response has arrived
+    duplex: bool,
+    /// Write the response body to a file instead of replying with it
+    response_file: Option<ResponseFile>,
+    /// The number of chunks to send before waiting for `read` or `set_active`
+    active: u32,
+    /// Set if other requests are waiting for the result of this one
+    coalesce_leader: Option<Leader<Coalesced>>,
+    /// Counts the bytes transferred, for `progress` reports
+    progress: Option<Arc<Progress>>,
 }
 
 impl Req {
@@ -267,7 +551,21 @@ impl Req {
         let CallerRef { mut env, ref_ } = self.caller_ref.take().unwrap();
         env.send_and_clear(&self.caller_pid, |env| f(env, ref_.load(env)))
     }
+    fn reply_resp(&mut self, resp: Resp) {
+        self.reply_final(|env, ref_| {
+            (
+                atoms::erqwest_response(),
+                ref_,
+                atoms::reply(),
+                resp.encode(env),
+            )
+                .encode(env)
+        });
+    }
     fn reply_error(&mut self, e: Error) {
+        if let Some(leader) = self.coalesce_leader.take() {
+            leader.finish(Err(e.clone()));
+        }
         self.reply_final(|env, ref_| {
             (atoms::erqwest_response(), ref_, atoms::error(), e).encode(env)
         })
@@ -275,32 +573,167 @@ impl Req {
     fn reply_none(&mut self) {
         self.caller_ref.take().unwrap();
     }
-    async fn run(mut self, req_data: ReqData) {
-        let builder = match req_data.decode() {
-            Ok(builder) => builder,
+    async fn run(mut self, mut req_data: ReqData) {
+        let client = req_data.client.clone();
+        let digest_auth = req_data.digest_auth.take();
+        let streamed_body =
+            self.req_body_channels.is_some() || matches!(req_data.body, Some(ReqBody::File(_)));
+        if digest_auth.is_some() && streamed_body {
+            self.reply_error(Error::from_reason(
+                ErrorCode::Request,
+                "digest_auth cannot be used with a streamed request body",
+            ));
+            return;
+        }
+        let transport = Arc::new(Transport {
+            client: client.clone(),
+            // digest_auth takes care of the Authorization header itself
+            oauth2: req_data.oauth2.take().filter(|_| digest_auth.is_none()),
+            digest_auth,
+            circuit: req_data.circuit.take(),
+            rate_limit: req_data.rate_limit.take(),
+        });
+        let retry = req_data.retry.take();
+        let hedge = req_data.hedge.take();
+        let mut hedged = None;
+        let limits = req_data.limits.take();
+        let coalesce = req_data.coalesce.take();
+        let cache = req_data.cache.take();
+        let mut attempts = 1;
+        if let Some(ReqBody::File(file)) = &mut req_data.body {
+            if let Err(e) = file.open().await {
+                self.reply_error(e);
+                return;
+            }
+        }
+        let mut request = match req_data.decode().and_then(|builder| Ok(builder.build()?)) {
+            Ok(request) => request,
             Err(e) => {
                 self.reply_error(e);
                 return;
             }
         };
-        let resp = builder.send();
-        tokio::pin!(resp);
-        let mut res = if let Some((mut tx, rx)) = self.req_body_channels.take() {
-            match self.stream_req(&mut resp, &mut tx, rx).await {
-                Some(Ok(res)) => res,
+        let mut cache_pending = None;
+        let mut cache_status = None;
+        let mut cache_hit = None;
+        if let Some(cache) = cache {
+            // streamed request bodies bypass the cache
+            if streamed_body {
+                cache.invalidate(&request);
+            } else {
+                let authenticated = transport.oauth2.is_some() || transport.digest_auth.is_some();
+                match cache.lookup(&mut request, authenticated).await {
+                    Some(Lookup::Hit(stored)) => cache_hit = Some(stored),
+                    Some(Lookup::Forward(pending)) => cache_pending = Some((cache, pending)),
+                    None => (),
+                }
+            }
+        }
+        if let Some(stored) = cache_hit {
+            let res = stored.into_response();
+            self.respond(res, None, None, Some(CacheStatus::Hit), None, None)
+                .await;
+            return;
+        }
+        if let Some((coalescer, key_headers)) = coalesce {
+            let key = key_headers.key(&request);
+            // a follower doesn't send anything, so its timeout is applied here
+            let deadline = request.timeout().map(|timeout| Instant::now() + *timeout);
+            loop {
+                match coalescer.join(key.clone()) {
+                    Role::Leader(leader) => {
+                        self.coalesce_leader = Some(leader);
+                        break;
+                    }
+                    Role::Follower(rx) => {
+                        let result = match deadline {
+                            Some(deadline) => match tokio::time::timeout_at(deadline, rx).await {
+                                Ok(result) => result,
+                                Err(_) => {
+                                    self.reply_error(Error::from_reason(
+                                        ErrorCode::Timeout,
+                                        "timed out waiting for a coalesced request",
+                                    ));
+                                    return;
+                                }
+                            },
+                            None => rx.await,
+                        };
+                        // otherwise the leader was cancelled, so try again
+                        if let Ok(result) = result {
+                            match result {
+                                Ok(resp) => self.reply_resp(resp),
+                                Err(e) => self.reply_error(e),
+                            }
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        // held until the response (including the body) is complete
+        let _slot = match &limits {
+            Some(limits) => match limits.acquire(request.url()).await {
+                Ok(slot) => Some(slot),
+                Err(e) => {
+                    self.reply_error(e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        // a copy of the request, for requesting the rest of the response body
+        // if the download is interrupted
+        let resume_request = match &self.response_file {
+            Some(file) if file.max_attempts.is_some() => request.try_clone(),
+            _ => None,
+        };
+        let mut duplex_upload = None;
+        let mut res = if let Some((tx, rx)) = self.req_body_channels.take() {
+            if let Some(oauth2) = &transport.oauth2 {
+                // a streamed body can't be replayed, so there is no retry on 401
+                if let Err(e) = oauth2.authorize(&client, &mut request).await {
+                    self.reply_error(e);
+                    return;
+                }
+            }
+            let permit = match transport.admit(request.url()).await {
+                Ok(permit) => permit,
+                Err(e) => {
+                    self.reply_error(e);
+                    return;
+                }
+            };
+            let mut upload = Upload::new(&self, tx, rx);
+            let resp = client.execute(request);
+            tokio::pin!(resp);
+            match upload.until_response(&mut resp, self.duplex).await {
+                Some(Ok(res)) => {
+                    if let Some(permit) = permit {
+                        permit.record(Ok(&res));
+                    }
+                    if self.duplex {
+                        duplex_upload = Some(upload);
+                    }
+                    res
+                }
                 Some(Err(e)) => {
                     // drop the request future before the tx, since
                     // closing the tx means "complete the request".
                     drop(resp);
-                    drop(tx);
-                    self.reply_error(e.into());
+                    drop(upload);
+                    let e = e.into();
+                    if let Some(permit) = permit {
+                        permit.record(Err(&e));
+                    }
+                    self.reply_error(e);
                     return;
                 }
                 None => {
                     // drop the request future before the tx, since
                     // closing the tx means "complete the request".
                     drop(resp);
-                    drop(tx);
+                    drop(upload);
                     // the client is not waiting for a reply (eg. has
                     // cancelled), so we don't reply
                     self.reply_none();
@@ -308,117 +741,163 @@ impl Req {
                 }
             }
         } else {
-            match resp.await {
-                Ok(res) => res,
-                Err(e) => {
-                    self.reply_error(e.into());
-                    return;
+            let method = request.method().clone();
+            loop {
+                // only requests without a streamed body can be cloned
+                let next = retry.as_ref().and_then(|_| request.try_clone());
+                let result = match &hedge {
+                    Some(hedge) => {
+                        let (result, won) = transport.send_hedged(request, hedge).await;
+                        hedged = Some(won);
+                        result
+                    }
+                    None => transport.send(request).await,
+                };
+                let delay = retry
+                    .as_ref()
+                    .and_then(|retry| retry.delay(attempts, &method, &result));
+                match (next, delay) {
+                    (Some(next), Some(delay)) => {
+                        drop(result);
+                        tokio::time::sleep(delay).await;
+                        request = next;
+                        attempts += 1;
+                    }
+                    _ => match result {
+                        Ok(res) => {
+                            // a file body counts itself as it is sent
+                            if let Some(progress) =
+                                self.progress.as_ref().filter(|_| !streamed_body)
+                            {
+                                progress.body_sent();
+                            }
+                            break res;
+                        }
+                        Err(e) => {
+                            self.reply_error(e);
+                            return;
+                        }
+                    },
                 }
             }
         };
+        let mut cache_writer = None;
+        if let Some((cache, pending)) = cache_pending {
+            let status = res.status().as_u16();
+            cache_status = Some(match cache.respond(pending, status, res.headers()).await {
+                Outcome::Revalidated(stored) => {
+                    res = stored.into_response();
+                    CacheStatus::Revalidated
+                }
+                Outcome::Store(writer) => {
+                    cache_writer = Some(*writer);
+                    CacheStatus::Miss
+                }
+                Outcome::Miss => CacheStatus::Miss,
+            });
+        }
+        let attempts = retry.map(|_| attempts);
+        if let Some(mut upload) = duplex_upload {
+            // the response body is streamed while the rest of the request
+            // body is sent, and a failure to send ends both
+            let mut respond =
+                Box::pin(self.respond(res, attempts, hedged, cache_status, cache_writer, None));
+            let e = tokio::select! {
+                () = &mut respond => {
+                    // the server may still be reading the request body, so it
+                    // is sent in full even though the response is complete
+                    if let Err(e) = upload.finish().await {
+                        upload.send_error(e);
+                    }
+                    return;
+                }
+                Err(e) = upload.finish() => e,
+            };
+            drop(respond);
+            self.reply_error(e);
+        } else {
+            let resume = resume_request.map(|request| Resume {
+                transport: &transport,
+                request,
+            });
+            self.respond(res, attempts, hedged, cache_status, cache_writer, resume)
+                .await;
+        }
+    }
+    /// Read or stream the response body and reply
+    async fn respond(
+        &mut self,
+        mut res: reqwest::Response,
+        attempts: Option<u32>,
+        hedged: Option<bool>,
+        cache: Option<CacheStatus>,
+        mut cache_writer: Option<cache::Writer>,
+        resume: Option<Resume<'_>>,
+    ) {
+        if let Some(progress) = &self.progress {
+            progress.set_received_total(res.content_length());
+        }
         let status = res.status().as_u16();
         // "steal" the headers to avoid a copy
         let mut headers = HeaderMap::new();
         mem::swap(res.headers_mut(), &mut headers);
-        if let Some(rx) = self.resp_stream_rx.take() {
+        if let Some(file) = self.response_file.take() {
+            let result = file
+                .write(
+                    &mut res,
+                    &headers,
+                    &mut cache_writer,
+                    resume,
+                    self.progress.as_ref(),
+                )
+                .await;
+            match result {
+                Ok(written) => {
+                    if let Some(writer) = cache_writer {
+                        writer.finish().await;
+                    }
+                    self.reply_resp(Resp {
+                        status,
+                        headers,
+                        body: Some(RespBody::File(file.path, written)),
+                        attempts,
+                        hedged,
+                        cache,
+                    });
+                }
+                Err(e) => self.reply_error(e),
+            }
+        } else if let Some(rx) = self.resp_stream_rx.take() {
             let partial_resp = Resp {
                 status,
                 headers,
                 body: None,
+                attempts,
+                hedged,
+                cache,
             };
-            self.stream_resp(res, rx, partial_resp).await;
+            self.stream_resp(res, rx, partial_resp, cache_writer).await;
         } else {
-            match res.bytes().await {
+            match progress::response_body(self.progress.as_ref(), res).await {
                 Ok(bytes) => {
+                    if let Some(mut writer) = cache_writer.take() {
+                        writer.write(&bytes).await;
+                        writer.finish().await;
+                    }
                     let resp = Resp {
                         status,
                         headers,
-                        body: Some(bytes),
+                        body: Some(RespBody::Bytes(bytes)),
+                        attempts,
+                        hedged,
+                        cache,
                     };
-                    self.reply_final(|env, ref_| {
-                        (
-                            atoms::erqwest_response(),
-                            ref_,
-                            atoms::reply(),
-                            resp.encode(env),
-                        )
-                            .encode(env)
-                    });
-                }
-                Err(e) => self.reply_error(e.into()),
-            }
-        }
-    }
-    /// Stream the request body and wait for the response. These two things need
-    /// to be combined, since a response can come at any time (even before the
-    /// request body is complete). Return values: `Ok(reply | error)` => send a
-    /// reply message, `None` => the stream was cancelled, end without replying.
-    async fn stream_req(
-        &mut self,
-        mut resp: &mut Pin<&mut impl Future<Output = reqwest::Result<reqwest::Response>>>,
-        tx: &mut Sender<Result<Vec<u8>, Infallible>>,
-        mut rx: UnboundedReceiver<SendCmd>,
-    ) -> Option<Result<reqwest::Response, Error>> {
-        let env = OwnedEnv::new();
-        let term_next = env.run(|e| {
-            let term = (
-                atoms::erqwest_response(),
-                &self.caller_ref.as_ref().unwrap(),
-                atoms::next(),
-            )
-                .encode(e);
-            e.send(&self.caller_pid, term);
-            env.save(term)
-        });
-        let mut fin = false;
-        loop {
-            tokio::select! {
-                next = rx.next(), if !fin =>
-                    match next {
-                        Some(SendCmd::Send(term_env, term)) => {
-                            let data = term_env.run(|e| term.load(e).decode_as_binary().map(|d| d.to_vec()).map_err(|_|
-                               Error::from_reason(
-                                    ErrorCode::Request,
-                                    "invalid iodata"
-                               )
-                            ));
-                            let feed = match data {
-                                Err(e) => return Some(Err(e)),
-                                Ok(data) => tx.feed(Ok(data))
-                            };
-                            tokio::select! {
-                                Ok(()) = feed =>
-                                    env.run(|env| env.send(&self.caller_pid, term_next.load(env))),
-                                // the caller is waiting for a response so we can reply immediately
-                                res = &mut resp => return Some(res.map_err(Error::from))
-                            }
-                        },
-                        Some(SendCmd::FinishSend) => {
-                            tx.close_channel();
-                            // now we just wait for the response
-                            fin = true;
-                        },
-                        None => {
-                            // the caller has not asked for a response and will
-                            // never be able to, exit without replying
-                            return None
-                        }
-                },
-                res = &mut resp => {
-                    if fin {
-                        // the caller is waiting for a response so reply immediately
-                        return Some(res.map_err(Error::from))
-                    } else {
-                        // the caller is not expecting a response yet so wait for the next command
-                        if rx.next().await.is_none() {
-                            // the caller has not asked for a response and never
-                            // can, so we exit without replying
-                            return None
-                        } else {
-                            return Some(res.map_err(Error::from))
-                        }
+                    if let Some(leader) = self.coalesce_leader.take() {
+                        leader.finish(Ok(resp.clone()));
                     }
+                    self.reply_resp(resp);
                 }
+                Err(e) => self.reply_error(e.into()),
             }
         }
     }
@@ -428,8 +907,9 @@ impl Req {
     async fn stream_resp(
         &mut self,
         mut resp: reqwest::Response,
-        mut rx: UnboundedReceiver<ReadOpts>,
+        mut rx: UnboundedReceiver<StreamCmd>,
         partial_resp: Resp,
+        mut cache_writer: Option<cache::Writer>,
     ) {
         let mut env = OwnedEnv::new();
         env.run(|env| {
@@ -444,56 +924,75 @@ impl Req {
                     .encode(env),
             )
         });
+        env.clear();
+        let mut active = self.active;
         let mut buf = Vec::new();
         loop {
-            match rx.next().await {
-                Some(opts) => {
-                    buf.clear();
-                    // TODO: use stream instead of resp directly
-                    match stream_response_chunk(&mut resp, opts, &mut buf).await {
-                        Ok(res) => {
-                            match res {
-                                IsFin::NoFin => {
-                                    env.run(|env| {
-                                        let mut bin = NewBinary::new(env, buf.len());
-                                        bin.as_mut_slice().copy_from_slice(&buf);
-                                        env.send(
-                                            &self.caller_pid,
-                                            (
-                                                atoms::erqwest_response(),
-                                                &self.caller_ref.as_ref().unwrap(),
-                                                atoms::chunk(),
-                                                Term::from(bin),
-                                            )
-                                                .encode(env),
-                                        )
-                                    });
-                                    env.clear();
+            let cmd = if active > 0 {
+                tokio::select! {
+                    cmd = rx.next() => cmd,
+                    res = resp.chunk() => {
+                        match res {
+                            Ok(Some(chunk)) => {
+                                progress::chunk_received(self.progress.as_ref(), &chunk);
+                                if let Some(writer) = &mut cache_writer {
+                                    writer.write(&chunk).await;
                                 }
-                                IsFin::Fin => {
-                                    // Before we send the reply, drop the rx to make
-                                    // sure that further calls to `read` fail
-                                    drop(rx);
-                                    self.reply_final(|env, ref_| {
-                                        let mut bin = NewBinary::new(env, buf.len());
-                                        bin.as_mut_slice().copy_from_slice(&buf);
+                                self.send_chunk(&mut env, &[chunk]);
+                                active -= 1;
+                                if active == 0 {
+                                    env.send_and_clear(&self.caller_pid, |env| {
                                         (
                                             atoms::erqwest_response(),
-                                            ref_,
-                                            atoms::fin(),
-                                            Term::from(bin),
+                                            self.caller_ref.as_ref().unwrap(),
+                                            atoms::passive(),
                                         )
                                             .encode(env)
                                     });
-                                    return;
                                 }
                             }
+                            Ok(None) => {
+                                self.finish_stream(rx, &[], cache_writer).await;
+                                return;
+                            }
+                            Err(e) => {
+                                // Before we send the reply, drop the rx to make
+                                // sure that further calls to `read` fail
+                                drop(rx);
+                                self.reply_error(e.into());
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                rx.next().await
+            };
+            match cmd {
+                Some(StreamCmd::SetActive(n)) => active = active.saturating_add(n),
+                Some(StreamCmd::Read(opts)) => {
+                    buf.clear();
+                    // TODO: use stream instead of resp directly
+                    let result = stream_response_chunk(
+                        &mut resp,
+                        opts,
+                        &mut buf,
+                        &mut cache_writer,
+                        self.progress.as_ref(),
+                    )
+                    .await;
+                    match result {
+                        Ok(IsFin::NoFin) => self.send_chunk(&mut env, &buf),
+                        Ok(IsFin::Fin) => {
+                            self.finish_stream(rx, &buf, cache_writer).await;
+                            return;
                         }
                         Err(e) => {
                             // Before we send the reply, drop the rx to make
                             // sure that further calls to `read` fail
                             drop(rx);
-                            self.reply_error(e.into());
+                            self.reply_error(e);
                             return;
                         }
                     }
@@ -506,6 +1005,39 @@ impl Req {
             }
         }
     }
+    fn send_chunk(&self, env: &mut OwnedEnv, chunks: &[Bytes]) {
+        env.send_and_clear(&self.caller_pid, |env| {
+            (
+                atoms::erqwest_response(),
+                self.caller_ref.as_ref().unwrap(),
+                atoms::chunk(),
+                make_binary_from_chunks(env, chunks),
+            )
+                .encode(env)
+        });
+    }
+    async fn finish_stream(
+        &mut self,
+        rx: UnboundedReceiver<StreamCmd>,
+        chunks: &[Bytes],
+        cache_writer: Option<cache::Writer>,
+    ) {
+        if let Some(writer) = cache_writer {
+            writer.finish().await;
+        }
+        // Before we send the reply, drop the rx to make sure that further
+        // calls to `read` fail
+        drop(rx);
+        self.reply_final(|env, ref_| {
+            (
+                atoms::erqwest_response(),
+                ref_,
+                atoms::fin(),
+                make_binary_from_chunks(env, chunks),
+            )
+                .encode(env)
+        });
+    }
 }
 
 impl Drop for Req {
@@ -529,8 +1061,11 @@ impl Drop for Req {
 async fn stream_response_chunk(
     response: &mut reqwest::Response,
     opts: ReadOpts,
-    buf: &mut Vec<u8>, // passed in so we can reuse the memory allocation between chunks
+    buf: &mut Vec<Bytes>, // passed in so we can reuse the memory allocation between chunks
+    cache_writer: &mut Option<cache::Writer>,
+    progress: Option<&Arc<Progress>>,
 ) -> Result<IsFin, Error> {
+    let mut len = 0;
     let timeout = OptionFuture::from(opts.period.map(tokio::time::sleep));
     tokio::pin!(timeout);
     loop {
@@ -538,8 +1073,13 @@ async fn stream_response_chunk(
             // TODO: is this cancellation safe? maybe safer to use a stream which is guaranteed?
             res = response.chunk() => match res {
                 Ok(Some(chunk)) => {
-                    buf.extend_from_slice(&chunk);
-                    if buf.len() >= opts.length {
+                    progress::chunk_received(progress, &chunk);
+                    if let Some(writer) = cache_writer {
+                        writer.write(&chunk).await;
+                    }
+                    len += chunk.len();
+                    buf.push(chunk);
+                    if len >= opts.length {
                         return Ok(IsFin::NoFin);
                     }
                 }
@@ -554,7 +1094,7 @@ async fn stream_response_chunk(
 pub struct ReqHandle {
     abort_handle: AbortHandle,
     req_body_tx: Option<mpsc::UnboundedSender<SendCmd>>,
-    resp_stream_tx: Option<mpsc::UnboundedSender<ReadOpts>>,
+    resp_stream_tx: Option<mpsc::UnboundedSender<StreamCmd>>,
 }
 
 /// Helper for decoding the `body` opt
@@ -572,7 +1112,206 @@ enum ResponseBody {
 
 enum ReqBody {
     Complete(SavedTerm),
-    Stream(Receiver<Result<Vec<u8>, Infallible>>),
+    Stream(Receiver<Result<Bytes, Infallible>>),
+    File(RequestFile),
+}
+
+/// The request body being streamed from erlang. The other end of `tx` is
+/// converted to a Stream and given to `reqwest`. We get new data from erlang on
+/// `rx` and feed it to `tx`. This allows us to provide backpressure by replying
+/// `next` after each chunk is successfully fed, or once it is queued if it fits
+/// in the `send_window`.
+struct Upload {
+    tx: Sender<Result<Bytes, Infallible>>,
+    rx: UnboundedReceiver<SendCmd>,
+    env: OwnedEnv,
+    caller_pid: LocalPid,
+    caller_ref: CallerRef,
+    window: Option<usize>,
+    /// Chunks which have been received from the caller but not yet fed to
+    /// `reqwest`, and their total size
+    queue: VecDeque<Bytes>,
+    queued: usize,
+    /// The number of `next` replies held back until the queue has drained to
+    /// within the window
+    held: usize,
+    /// Set once the caller has called `finish_send`
+    fin: bool,
+}
+
+impl Upload {
+    fn new(
+        task: &Req,
+        tx: Sender<Result<Bytes, Infallible>>,
+        rx: UnboundedReceiver<SendCmd>,
+    ) -> Upload {
+        let mut upload = Upload {
+            tx,
+            rx,
+            env: OwnedEnv::new(),
+            caller_pid: task.caller_pid,
+            caller_ref: task.caller_ref.as_ref().unwrap().copy(),
+            window: task.send_window,
+            queue: VecDeque::new(),
+            queued: 0,
+            held: 0,
+            fin: false,
+        };
+        upload.send_next(upload.window.unwrap_or(0));
+        upload
+    }
+    /// Stream the request body and wait for the response. These two things need
+    /// to be combined, since a response can come at any time (even before the
+    /// request body is complete). Return values: `Ok(reply | error)` => send a
+    /// reply message, `None` => the stream was cancelled, end without replying.
+    /// With `duplex` the response is returned as soon as it arrives, and the
+    /// rest of the body is streamed by `finish`.
+    async fn until_response(
+        &mut self,
+        mut resp: &mut Pin<&mut impl Future<Output = reqwest::Result<reqwest::Response>>>,
+        duplex: bool,
+    ) -> Option<Result<reqwest::Response, Error>> {
+        loop {
+            tokio::select! {
+                step = self.step() => match step {
+                    Some(Ok(())) => (),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {
+                        // the caller has not asked for a response and will
+                        // never be able to, exit without replying
+                        return None
+                    }
+                },
+                res = &mut resp => {
+                    if duplex || self.fin || self.held > 0 {
+                        // the caller is waiting for a response or for `next`
+                        // (or always expects one with `duplex`) so reply
+                        // immediately
+                        return Some(res.map_err(Error::from))
+                    } else {
+                        // the caller is not expecting a response yet so wait for the next command
+                        if self.rx.next().await.is_none() {
+                            // the caller has not asked for a response and never
+                            // can, so we exit without replying
+                            return None
+                        } else {
+                            return Some(res.map_err(Error::from))
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// Stream the rest of the body after the response has arrived, until the
+    /// caller calls `finish_send` or goes away
+    async fn finish(&mut self) -> Result<(), Error> {
+        while !(self.fin && self.queue.is_empty()) {
+            match self.step().await {
+                Some(Ok(())) => (),
+                Some(Err(e)) => return Err(e),
+                // the response stream will notice too
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+    /// Handle a command from the caller, or feed a chunk to `reqwest` once it
+    /// is ready for more. Returns `None` if the caller has gone away.
+    async fn step(&mut self) -> Option<Result<(), Error>> {
+        let window = self.window.unwrap_or(0);
+        tokio::select! {
+            next = self.rx.next(), if !self.fin =>
+                match next {
+                    Some(SendCmd::Send(term_env, term)) => {
+                        let term_env = PinnedEnv::new(term_env);
+                        let chunks = term_env.run(|e| iodata_to_chunks(&term_env, term.load(e)).ok_or_else(||
+                           Error::from_reason(
+                                ErrorCode::Request,
+                                "invalid iodata"
+                           )
+                        ));
+                        match chunks {
+                            Err(e) => return Some(Err(e)),
+                            Ok(chunks) => {
+                                for data in chunks {
+                                    self.queued += data.len();
+                                    self.queue.push_back(data);
+                                }
+                            }
+                        }
+                        if self.held == 0 && self.queued <= window {
+                            self.send_next(window - self.queued);
+                        } else {
+                            self.held += 1;
+                        }
+                    },
+                    Some(SendCmd::FinishSend) => {
+                        if self.queue.is_empty() {
+                            self.tx.close_channel();
+                        }
+                        // now we just wait for the response
+                        self.fin = true;
+                    },
+                    None => return None
+            },
+            ready = poll_ready(&mut self.tx), if !self.queue.is_empty() => {
+                let data = self.queue.pop_front().unwrap();
+                self.queued -= data.len();
+                if ready.is_err() || self.tx.start_send(Ok(data)).is_err() {
+                    // `reqwest` has stopped reading the body, so the
+                    // response (or an error) is on its way
+                    self.queue.clear();
+                    self.queued = 0;
+                }
+                if self.queued <= window {
+                    for _ in 0..mem::take(&mut self.held) {
+                        self.send_next(window - self.queued);
+                    }
+                }
+                if self.fin && self.queue.is_empty() {
+                    self.tx.close_channel();
+                }
+            },
+        }
+        Some(Ok(()))
+    }
+    /// Tell the caller that it can send more of the request body. With a
+    /// `send_window` this includes the number of bytes which can be sent
+    /// before `next` is held back.
+    fn send_next(&mut self, credit: usize) {
+        let caller_ref = &self.caller_ref;
+        let window = self.window;
+        self.env
+            .send_and_clear(&self.caller_pid, |env| match window {
+                Some(_) => {
+                    (atoms::erqwest_response(), caller_ref, atoms::next(), credit).encode(env)
+                }
+                None => (atoms::erqwest_response(), caller_ref, atoms::next()).encode(env),
+            });
+    }
+    /// Fail the caller's pending `send`, once the final reply has already
+    /// been sent
+    fn send_error(&mut self, e: Error) {
+        let caller_ref = &self.caller_ref;
+        self.env.send_and_clear(&self.caller_pid, |env| {
+            (atoms::erqwest_response(), caller_ref, atoms::error(), e).encode(env)
+        });
+    }
+}
+
+fn poll_ready<T>(tx: &mut Sender<T>) -> impl Future<Output = Result<(), mpsc::SendError>> + '_ {
+    poll_fn(move |cx| tx.poll_ready(cx))
+}
+
+/// A request which has been decoded, but not started yet
+struct Prepared {
+    req: Req,
+    req_data: ReqData,
+    req_body_tx: Option<mpsc::UnboundedSender<SendCmd>>,
+    resp_stream_tx: Option<mpsc::UnboundedSender<StreamCmd>>,
+    headers: usize,
+    /// Used in `progress` reports instead of the caller ref, if given
+    progress_ref: Option<CallerRef>,
 }
 
 #[rustler::nif]
@@ -583,6 +1322,112 @@ fn req(
     caller_ref: Term,
     opts: Term,
 ) -> NifResult<ResourceArc<ReqHandle>> {
+    let prepared = prepare(env, &resource, pid, caller_ref, opts)?;
+    req_consume_timeslice(env, prepared.headers);
+    spawn(&resource, prepared, None)
+}
+
+/// Start a list of `{Ref, Opts}` requests, as if `req` was called for each of
+/// them. Since every request is decoded before any of them is started, a bad
+/// request means that nothing is sent. If the runtime turns out to be shutting
+/// down part way through, the requests which were already started are
+/// cancelled (see `spawn_all`).
+#[rustler::nif(schedule = "DirtyCpu")]
+fn req_many(
+    env: Env,
+    resource: ResourceArc<ClientResource>,
+    pid: LocalPid,
+    reqs: ListIterator,
+) -> NifResult<Vec<ResourceArc<ReqHandle>>> {
+    let mut prepared = Vec::new();
+    for r in reqs {
+        let (caller_ref, opts): (Term, Term) = r.decode()?;
+        prepared.push(prepare(env, &resource, pid, caller_ref, opts)?);
+    }
+    spawn_all(&resource, prepared, None)
+}
+
+/// Run a list of requests with at most `concurrency` in flight, replying to
+/// the `I`th request (counting from 1) with `{Ref, I}` as the ref. Once
+/// `timeout` has passed, the requests which haven't completed are cancelled.
+/// Returns a handle for each request, in the same order.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn req_all(
+    env: Env,
+    resource: ResourceArc<ClientResource>,
+    pid: LocalPid,
+    caller_ref: Term,
+    reqs: ListIterator,
+    opts: Term,
+) -> NifResult<Vec<ResourceArc<ReqHandle>>> {
+    let mut concurrency = None;
+    let mut timeout = None;
+    for (k, v) in opts.decode::<MapIterator>()? {
+        let k: Atom = k.decode()?;
+        if k == atoms::concurrency() {
+            concurrency = maybe_limit(v)?;
+        } else if k == atoms::timeout() {
+            timeout = maybe_timeout(v)?;
+        } else {
+            return Err(rustler::Error::RaiseTerm(Box::new((atoms::bad_opt(), k))));
+        }
+    }
+    let mut prepared = Vec::new();
+    for (i, opts) in reqs.enumerate() {
+        let ref_ = (caller_ref, i + 1).encode(env);
+        let p = prepare(env, &resource, pid, ref_, opts)?;
+        // there is no way to send or read a stream
+        if p.req.req_body_channels.is_some() || p.req.resp_stream_rx.is_some() {
+            return Err(rustler::Error::BadArg);
+        }
+        prepared.push(p);
+    }
+    let batch = Batch {
+        // more permits than requests would make no difference
+        permits: concurrency.map(|n| Arc::new(Semaphore::new(n.min(prepared.len())))),
+        deadline: timeout.map(|timeout| Instant::now() + timeout),
+    };
+    spawn_all(&resource, prepared, Some(&batch))
+}
+
+/// Spawn every request, or none of them: if one can't be spawned (because the
+/// runtime is shutting down), the ones before it are cancelled again, and reply
+/// with an error with code `cancelled`.
+fn spawn_all(
+    resource: &ClientResource,
+    prepared: Vec<Prepared>,
+    batch: Option<&Batch>,
+) -> NifResult<Vec<ResourceArc<ReqHandle>>> {
+    let mut handles = Vec::with_capacity(prepared.len());
+    for prepared in prepared {
+        match spawn(resource, prepared, batch) {
+            Ok(handle) => handles.push(handle),
+            Err(e) => {
+                for handle in handles {
+                    handle.abort_handle.abort();
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(handles)
+}
+
+/// Shared by the requests started together by `req_all`
+struct Batch {
+    /// Limits the number of requests in flight at once
+    permits: Option<Arc<Semaphore>>,
+    /// When the requests which haven't completed are cancelled
+    deadline: Option<Instant>,
+}
+
+fn prepare(
+    env: Env,
+    resource: &ClientResource,
+    pid: LocalPid,
+    caller_ref: Term,
+    opts: Term,
+) -> NifResult<Prepared> {
     // returns BadArg if the client was already closed with close_client
     let client = resource
         .client
@@ -595,11 +1440,24 @@ fn req(
     let mut req_body_channels = None;
     let mut resp_stream_tx = None;
     let mut resp_stream_rx = None;
+    let mut active = 0;
+    let mut send_window = None;
+    let mut duplex = false;
+    let mut response_file = None;
+    let mut resume = None;
     let mut headers = None;
     let mut url = None;
     let mut body = None;
     let mut timeout = None;
     let mut method = None;
+    let mut digest_auth = None;
+    let mut aws_sigv4 = None;
+    let mut sign = None;
+    let mut retry = resource.retry.clone();
+    let mut hedge = None;
+    let mut coalesce = None;
+    let mut progress = None;
+    let mut progress_ref = None;
     let owned_env = OwnedEnv::new();
 
     for (k, v) in opts.decode::<MapIterator>()? {
@@ -619,8 +1477,10 @@ fn req(
             }
             headers = Some(owned_headers);
         } else if k == atoms::body() {
-            if v.decode::<StreamBody>().is_ok() {
-                let (tx, rx) = mpsc::channel::<Result<Vec<u8>, Infallible>>(0);
+            if let Some(file) = RequestFile::decode(v)? {
+                body = Some(ReqBody::File(file));
+            } else if v.decode::<StreamBody>().is_ok() {
+                let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(0);
                 let (body_tx, body_rx0) = mpsc::unbounded();
                 body = Some(ReqBody::Stream(rx));
                 req_body_tx = Some(body_tx);
@@ -632,22 +1492,152 @@ fn req(
                 ));
             }
         } else if k == atoms::response_body() {
-            match v.decode()? {
-                ResponseBody::Complete => (),
-                ResponseBody::Stream => {
-                    let (tx, rx) = mpsc::unbounded();
-                    resp_stream_tx = Some(tx);
-                    resp_stream_rx = Some(rx);
+            if let Some(file) = ResponseFile::decode(v)? {
+                response_file = Some(file);
+            } else {
+                // `{active, N}` streams N chunks before waiting for `set_active`
+                let mode = match v.decode::<(Atom, u32)>() {
+                    Ok((tag, n)) if tag == atoms::active() && n > 0 => {
+                        active = n;
+                        ResponseBody::Stream
+                    }
+                    _ => v.decode()?,
+                };
+                match mode {
+                    ResponseBody::Complete => (),
+                    ResponseBody::Stream => {
+                        let (tx, rx) = mpsc::unbounded();
+                        resp_stream_tx = Some(tx);
+                        resp_stream_rx = Some(rx);
+                    }
                 }
             }
         } else if k == atoms::timeout() {
             timeout = maybe_timeout(v)?;
+        } else if k == atoms::resume() {
+            resume = ResponseFile::decode_resume(env, v)?;
+        } else if k == atoms::duplex() {
+            duplex = v.decode()?;
+        } else if k == atoms::progress() {
+            let (p, ref_) = Progress::decode(v)?;
+            progress = Some(p);
+            progress_ref = ref_.map(|ref_| ref_.into());
+        } else if k == atoms::send_window() {
+            match v.decode::<usize>()? {
+                0 => return Err(rustler::Error::BadArg),
+                n => send_window = Some(n),
+            }
+        } else if k == atoms::digest_auth() {
+            let (username, password): (String, String) = v.decode()?;
+            // the username is sent in a header
+            HeaderValue::from_str(&username).map_err(|_| rustler::Error::BadArg)?;
+            digest_auth = Some(DigestAuth {
+                username,
+                password,
+                nonces: resource.digest_nonces.clone(),
+            });
+        } else if k == atoms::retry() {
+            retry = RetryPolicy::decode(env, v, resource.retry.as_ref())?;
+        } else if k == atoms::coalesce() {
+            coalesce = match v.decode::<bool>() {
+                Ok(true) => Some(KeyHeaders::All),
+                Ok(false) => None,
+                Err(_) => {
+                    let mut names = Vec::new();
+                    for name in v
+                        .map_get(atoms::headers().encode(env))?
+                        .decode::<ListIterator>()?
+                    {
+                        names.push(
+                            HeaderName::from_bytes(name.decode::<Binary>()?.as_slice())
+                                .map_err(|_| rustler::Error::BadArg)?,
+                        );
+                    }
+                    Some(KeyHeaders::Only(names))
+                }
+            };
+        } else if k == atoms::hedge() {
+            hedge = Some(Hedge {
+                delay: Duration::from_millis(v.map_get(atoms::delay().encode(env))?.decode()?),
+                max_extra: match v.map_get(atoms::max_extra().encode(env)) {
+                    Ok(max_extra) => max_extra.decode()?,
+                    Err(_) => 1,
+                },
+            });
+        } else if k == atoms::sign() {
+            sign = Some(Sign::decode(env, v)?);
+        } else if k == atoms::aws_sigv4() {
+            let SigV4Base {
+                access_key,
+                secret_key,
+                region,
+                service,
+            } = v.decode()?;
+            let session_token = match v.map_get(atoms::session_token().encode(env)) {
+                Ok(term) => Some(term.decode::<String>()?),
+                Err(_) => None,
+            };
+            // these end up in headers
+            for s in [&access_key, &region, &service]
+                .iter()
+                .copied()
+                .chain(session_token.as_ref())
+            {
+                HeaderValue::from_str(s).map_err(|_| rustler::Error::BadArg)?;
+            }
+            aws_sigv4 = Some(SigV4 {
+                access_key,
+                secret_key,
+                session_token,
+                region,
+                service,
+            });
         } else {
             return Err(rustler::Error::RaiseTerm(Box::new((atoms::bad_opt(), k))));
         }
     }
 
-    req_consume_timeslice(env, headers.as_ref().map(|h| h.len()).unwrap_or(0));
+    // every copy of a hedged request must be safe to send, and a streamed body
+    // (including one read from a file) can only be sent once
+    let streamed_body = req_body_channels.is_some() || matches!(body, Some(ReqBody::File(_)));
+    if hedge.is_some()
+        && (streamed_body
+            || !matches!(
+                method,
+                Some(Method::Get | Method::Head | Method::Options | Method::Trace)
+            ))
+    {
+        return Err(rustler::Error::BadArg);
+    }
+    // only downloads to a file can be resumed, and only `get` requests can be
+    // sent again
+    if let Some(max_attempts) = resume {
+        match &mut response_file {
+            Some(file) if !streamed_body && matches!(method, Some(Method::Get)) => {
+                file.max_attempts = Some(max_attempts)
+            }
+            _ => return Err(rustler::Error::BadArg),
+        }
+    }
+    // both bodies are streamed at the same time
+    if duplex && (req_body_channels.is_none() || resp_stream_rx.is_none()) {
+        return Err(rustler::Error::BadArg);
+    }
+    // only requests with nothing to stream can share a response, and digest
+    // credentials are added too late to be part of the key
+    if coalesce.is_some()
+        && (streamed_body
+            || digest_auth.is_some()
+            || resp_stream_rx.is_some()
+            || response_file.is_some()
+            || !matches!(method, Some(Method::Get | Method::Head)))
+    {
+        return Err(rustler::Error::BadArg);
+    }
+
+    let num_headers = headers.as_ref().map(|h| h.len()).unwrap_or(0);
+    // digest_auth must not be preceded by sending the password in the clear
+    let netrc = resource.netrc.clone().filter(|_| digest_auth.is_none());
 
     let req_data = ReqData {
         client,
@@ -657,20 +1647,93 @@ fn req(
         method: method.ok_or(rustler::Error::BadArg)?,
         body,
         timeout,
+        digest_auth,
+        aws_sigv4,
+        sign,
+        oauth2: resource.oauth2.clone(),
+        netrc,
+        retry,
+        circuit: resource.circuit.clone(),
+        limits: resource.limits.clone(),
+        rate_limit: resource.rate_limit.clone(),
+        hedge,
+        coalesce: coalesce.map(|key_headers| (resource.coalescer.clone(), key_headers)),
+        cache: resource.cache.clone(),
+        progress: progress.clone(),
     };
-    let req = Req {
+    let task = Req {
         caller_ref: Some(caller_ref.into()),
         caller_pid: pid,
         dropped_on_initial_thread: Arc::new(AtomicBool::new(false)),
         req_body_channels,
         resp_stream_rx,
+        active,
+        send_window,
+        duplex,
+        response_file,
         initial_thread: thread::current().id(),
+        coalesce_leader: None,
+        progress,
     };
+    Ok(Prepared {
+        req: task,
+        req_data,
+        req_body_tx,
+        resp_stream_tx,
+        headers: num_headers,
+        progress_ref,
+    })
+}
+
+fn spawn(
+    resource: &ClientResource,
+    prepared: Prepared,
+    batch: Option<&Batch>,
+) -> NifResult<ResourceArc<ReqHandle>> {
+    let Prepared {
+        req: task,
+        req_data,
+        req_body_tx,
+        resp_stream_tx,
+        progress_ref,
+        ..
+    } = prepared;
     // This allows us to detect if the future was immediately dropped (ie. not
     // sent to another thread), which indicates that the Runtime is shutting
     // down or has shut down.
-    let dropped_on_initial_thread = req.dropped_on_initial_thread.clone();
-    let fut = req.run(req_data);
+    let dropped_on_initial_thread = task.dropped_on_initial_thread.clone();
+    let report = task.progress.clone().map(|progress| {
+        progress.report(progress_ref.unwrap_or_else(|| task.caller_ref.as_ref().unwrap().copy()))
+    });
+    let fut = task.run(req_data);
+    // progress is reported until the request is complete
+    let fut = async move {
+        match report {
+            Some(report) => tokio::select! {
+                () = fut => (),
+                () = report => (),
+            },
+            None => fut.await,
+        }
+    };
+    let permits = batch.and_then(|b| b.permits.clone());
+    let deadline = batch.and_then(|b| b.deadline);
+    let fut = async move {
+        let queued = async move {
+            let _permit = match permits {
+                Some(permits) => Some(permits.acquire_owned().await.unwrap()),
+                None => None,
+            };
+            fut.await
+        };
+        match deadline {
+            // dropping a request which is queued or still running cancels it
+            Some(deadline) => {
+                let _ = tokio::time::timeout_at(deadline, queued).await;
+            }
+            None => queued.await,
+        }
+    };
     let (abort_handle, abort_registration) = AbortHandle::new_pair();
     resource
         .runtime
@@ -767,9 +1830,23 @@ fn read<'a>(
     }
     let opts = ReadOpts { length, period };
     if let Some(resp_stream_tx) = req_handle.resp_stream_tx.as_ref() {
-        if resp_stream_tx.unbounded_send(opts).is_ok() {
+        if resp_stream_tx.unbounded_send(StreamCmd::Read(opts)).is_ok() {
             return Ok(atoms::ok().encode(env));
         }
     }
     Err(rustler::Error::BadArg)
 }
+
+/// Send up to `n` more chunks of the response body without waiting for `read`
+#[rustler::nif]
+fn set_active(req_handle: ResourceArc<ReqHandle>, n: u32) -> NifResult<Atom> {
+    if let Some(resp_stream_tx) = req_handle.resp_stream_tx.as_ref() {
+        if resp_stream_tx
+            .unbounded_send(StreamCmd::SetActive(n))
+            .is_ok()
+        {
+            return Ok(atoms::ok());
+        }
+    }
+    Err(rustler::Error::BadArg)
+}
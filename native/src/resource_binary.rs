@@ -0,0 +1,61 @@
+//! Binaries backed by a `Bytes` buffer, so that response bodies reach the VM
+//! without being copied. The buffer is owned by a resource, which the binary
+//! keeps alive until it is garbage collected.
+
+use std::os::raw::c_void;
+
+use bytes::Bytes;
+use rustler::resource::ResourceTypeProvider;
+use rustler::types::binary::NewBinary;
+use rustler::wrapper::resource::get_resource;
+use rustler::{Encoder, Env, ResourceArc, Term};
+
+pub struct BytesResource(Bytes);
+
+/// Binaries up to this size are stored on the process heap by the VM, so
+/// copying them is cheaper than sharing them
+pub const HEAP_BINARY_LIMIT: usize = 64;
+
+pub fn make_binary(env: Env, bytes: Bytes) -> Term {
+    if bytes.len() <= HEAP_BINARY_LIMIT {
+        return copy(env, &[bytes]);
+    }
+    let resource = ResourceArc::new(BytesResource(bytes));
+    let term = resource.encode(env);
+    let data = &resource.0;
+    unsafe {
+        let obj = get_resource(
+            env.as_c_arg(),
+            term.as_c_arg(),
+            BytesResource::get_type().res,
+        )
+        .unwrap();
+        Term::new(
+            env,
+            rustler_sys::enif_make_resource_binary(
+                env.as_c_arg(),
+                obj,
+                data.as_ptr() as *const c_void,
+                data.len(),
+            ),
+        )
+    }
+}
+
+/// A single chunk is passed on as it is, several are copied into one binary
+pub fn make_binary_from_chunks<'a>(env: Env<'a>, chunks: &[Bytes]) -> Term<'a> {
+    match chunks {
+        [chunk] => make_binary(env, chunk.clone()),
+        _ => copy(env, chunks),
+    }
+}
+
+fn copy<'a>(env: Env<'a>, chunks: &[Bytes]) -> Term<'a> {
+    let mut bin = NewBinary::new(env, chunks.iter().map(Bytes::len).sum());
+    let mut offset = 0;
+    for chunk in chunks {
+        bin.as_mut_slice()[offset..offset + chunk.len()].copy_from_slice(chunk);
+        offset += chunk.len();
+    }
+    bin.into()
+}
@@ -0,0 +1,193 @@
+//! Retrying transient failures with backoff.
+
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::RETRY_AFTER;
+use rustler::{Atom, Encoder, Env, ListIterator, NifResult, NifUnitEnum, Term};
+
+use crate::atoms;
+use crate::req::{Error, ErrorCode};
+
+#[derive(NifUnitEnum, PartialEq)]
+enum BackoffType {
+    Exponential,
+    Constant,
+}
+
+/// Helper for decoding the `methods` opt
+#[derive(NifUnitEnum)]
+enum MethodSet {
+    Idempotent,
+    All,
+}
+
+#[derive(Clone)]
+enum Backoff {
+    Exponential { base: Duration, max: Duration },
+    Constant(Duration),
+}
+
+#[derive(Clone)]
+enum Methods {
+    Idempotent,
+    All,
+    Only(Vec<reqwest::Method>),
+}
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: bool,
+    on_status: Vec<u16>,
+    methods: Methods,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Backoff::Exponential {
+                base: Duration::from_millis(100),
+                max: Duration::from_secs(10),
+            },
+            jitter: true,
+            on_status: vec![429, 502, 503],
+            methods: Methods::Idempotent,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Decode the `retry` opt. Keys which are not present are taken from
+    /// `base`, which makes it possible to override parts of a client's policy
+    /// for a single request. `false` disables retries.
+    pub fn decode(env: Env, term: Term, base: Option<&RetryPolicy>) -> NifResult<Option<Self>> {
+        if let Ok(enabled) = term.decode::<bool>() {
+            return Ok(if enabled {
+                Some(base.cloned().unwrap_or_default())
+            } else {
+                None
+            });
+        }
+        let mut policy = base.cloned().unwrap_or_default();
+        let get = |k: Atom| term.map_get(k.encode(env)).ok();
+        if let Some(v) = get(atoms::max_attempts()) {
+            policy.max_attempts = v.decode()?;
+            if policy.max_attempts == 0 {
+                return Err(rustler::Error::BadArg);
+            }
+        }
+        if let Some(v) = get(atoms::backoff()) {
+            policy.backoff = match rustler::types::tuple::get_tuple(v)?[..] {
+                [t, base, max] if t.decode::<BackoffType>()? == BackoffType::Exponential => {
+                    Backoff::Exponential {
+                        base: Duration::from_millis(base.decode()?),
+                        max: Duration::from_millis(max.decode()?),
+                    }
+                }
+                [t, delay] if t.decode::<BackoffType>()? == BackoffType::Constant => {
+                    Backoff::Constant(Duration::from_millis(delay.decode()?))
+                }
+                _ => return Err(rustler::Error::BadArg),
+            };
+        }
+        if let Some(v) = get(atoms::jitter()) {
+            policy.jitter = v.decode()?;
+        }
+        if let Some(v) = get(atoms::on_status()) {
+            policy.on_status = v.decode()?;
+        }
+        if let Some(v) = get(atoms::methods()) {
+            policy.methods = match v.decode::<MethodSet>() {
+                Ok(MethodSet::Idempotent) => Methods::Idempotent,
+                Ok(MethodSet::All) => Methods::All,
+                Err(_) => Methods::Only(
+                    v.decode::<ListIterator>()?
+                        .map(|m| m.decode::<crate::req::Method>().map(Into::into))
+                        .collect::<NifResult<_>>()?,
+                ),
+            };
+        }
+        Ok(Some(policy))
+    }
+
+    fn allows_method(&self, method: &reqwest::Method) -> bool {
+        use reqwest::Method;
+        match &self.methods {
+            Methods::Idempotent => matches!(
+                *method,
+                Method::GET
+                    | Method::HEAD
+                    | Method::OPTIONS
+                    | Method::TRACE
+                    | Method::PUT
+                    | Method::DELETE
+            ),
+            Methods::All => true,
+            Methods::Only(methods) => methods.contains(method),
+        }
+    }
+
+    /// Decide whether to make another attempt after `attempts` attempts, and
+    /// how long to wait first. A `Retry-After` header is honoured, but not if
+    /// it asks us to wait longer than the maximum backoff.
+    pub fn delay(
+        &self,
+        attempts: u32,
+        method: &reqwest::Method,
+        result: &Result<reqwest::Response, Error>,
+    ) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let retry_after = match result {
+            // the request was never sent, so this is safe for any method
+            Err(e) if matches!(e.code, ErrorCode::Connect) => None,
+            Err(e) if matches!(e.code, ErrorCode::Timeout) && self.allows_method(method) => None,
+            Ok(resp)
+                if self.on_status.contains(&resp.status().as_u16())
+                    && self.allows_method(method) =>
+            {
+                resp.headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_retry_after)
+            }
+            _ => return None,
+        };
+        let max = match self.backoff {
+            Backoff::Exponential { max, .. } => max,
+            Backoff::Constant(delay) => delay,
+        };
+        match retry_after {
+            Some(delay) if delay > max => None,
+            Some(delay) => Some(delay),
+            None => Some(self.backoff(attempts)),
+        }
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Exponential { base, max } => base
+                .checked_mul(2u32.saturating_pow(attempts - 1))
+                .map_or(max, |d| d.min(max)),
+            Backoff::Constant(delay) => delay,
+        };
+        if self.jitter {
+            delay.mul_f64(rand::random())
+        } else {
+            delay
+        }
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|t| t.duration_since(SystemTime::now()).unwrap_or_default()),
+    }
+}
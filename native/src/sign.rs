@@ -0,0 +1,145 @@
+//! HMAC signatures over a canonical string built from parts of the request, as
+//! used by many webhook-style APIs.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, Url};
+use rustler::{Binary, Encoder, Env, ListIterator, NifResult, NifUnitEnum, Term};
+use sha2::{Sha256, Sha512};
+
+use crate::atoms;
+
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+/// Helper for decoding the non-literal parts of the `template` opt
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum Field {
+    Timestamp,
+    Method,
+    Path,
+    Body,
+}
+
+pub enum Part {
+    Field(Field),
+    Literal(Vec<u8>),
+}
+
+pub struct Sign {
+    pub algorithm: Algorithm,
+    pub encoding: Encoding,
+    pub key: Vec<u8>,
+    pub header: HeaderName,
+    pub timestamp_header: Option<HeaderName>,
+    pub template: Vec<Part>,
+}
+
+impl Sign {
+    pub fn decode(env: Env, term: Term) -> NifResult<Sign> {
+        let get = |k: rustler::Atom| term.map_get(k.encode(env));
+        let header_name = |t: Term| {
+            HeaderName::from_bytes(t.decode::<Binary>()?.as_slice())
+                .map_err(|_| rustler::Error::BadArg)
+        };
+        let mut template = Vec::new();
+        for part in get(atoms::template())?.decode::<ListIterator>()? {
+            template.push(match part.decode() {
+                Ok(field) => Part::Field(field),
+                Err(_) => Part::Literal(part.decode::<Binary>()?.as_slice().to_vec()),
+            });
+        }
+        Ok(Sign {
+            algorithm: get(atoms::algorithm())?.decode()?,
+            encoding: match get(atoms::encoding()) {
+                Ok(encoding) => encoding.decode()?,
+                Err(_) => Encoding::Hex,
+            },
+            key: get(atoms::key())?.decode_as_binary()?.as_slice().to_vec(),
+            header: header_name(get(atoms::header())?)?,
+            timestamp_header: match get(atoms::timestamp_header()) {
+                Ok(name) => Some(header_name(name)?),
+                Err(_) => None,
+            },
+            template,
+        })
+    }
+
+    pub fn signs_body(&self) -> bool {
+        self.template
+            .iter()
+            .any(|p| matches!(p, Part::Field(Field::Body)))
+    }
+
+    /// Add the signature header (and the timestamp header, if configured) to
+    /// `headers`. If the caller has already set the timestamp header, its
+    /// value is signed instead of the current time.
+    pub fn sign(&self, method: &Method, url: &Url, headers: &mut HeaderMap, body: &[u8]) {
+        let timestamp = match &self.timestamp_header {
+            Some(name) => match headers.get(name) {
+                Some(value) => value.as_bytes().to_vec(),
+                None => {
+                    let now = unix_time();
+                    headers.insert(name.clone(), HeaderValue::from(now));
+                    now.to_string().into_bytes()
+                }
+            },
+            None => unix_time().to_string().into_bytes(),
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let signature = match self.algorithm {
+            Algorithm::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+                self.feed(&mut mac, &timestamp, method, &path, body);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.key).unwrap();
+                self.feed(&mut mac, &timestamp, method, &path, body);
+                mac.finalize().into_bytes().to_vec()
+            }
+        };
+        let encoded = match self.encoding {
+            Encoding::Hex => signature.iter().map(|b| format!("{:02x}", b)).collect(),
+            Encoding::Base64 => base64::encode(&signature),
+        };
+        // hex and base64 are always valid header values
+        headers.insert(
+            self.header.clone(),
+            HeaderValue::from_str(&encoded).unwrap(),
+        );
+    }
+
+    fn feed(&self, mac: &mut impl Mac, timestamp: &[u8], method: &Method, path: &str, body: &[u8]) {
+        for part in &self.template {
+            match part {
+                Part::Field(Field::Timestamp) => mac.update(timestamp),
+                Part::Field(Field::Method) => mac.update(method.as_str().as_bytes()),
+                Part::Field(Field::Path) => mac.update(path.as_bytes()),
+                Part::Field(Field::Body) => mac.update(body),
+                Part::Literal(bytes) => mac.update(bytes),
+            }
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,231 @@
+//! AWS Signature Version 4, see
+//! https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, HOST};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+
+/// Everything except the unreserved characters `A-Za-z0-9-._~`
+const AWS_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const X_AMZ_DATE: &str = "x-amz-date";
+const X_AMZ_SECURITY_TOKEN: &str = "x-amz-security-token";
+const X_AMZ_CONTENT_SHA256: &str = "x-amz-content-sha256";
+
+pub struct SigV4 {
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+    pub region: String,
+    pub service: String,
+}
+
+/// The request body as far as signing is concerned
+pub enum Payload<'a> {
+    Bytes(&'a [u8]),
+    Unsigned,
+}
+
+impl SigV4 {
+    /// Add the `Authorization` header (and the `x-amz-*` headers it depends
+    /// on) to `headers`. If the caller has already set `x-amz-date`, it is
+    /// used as the signing time.
+    pub fn sign(&self, method: &Method, url: &Url, headers: &mut HeaderMap, payload: Payload) {
+        let amz_date = match headers.get(X_AMZ_DATE).and_then(|v| v.to_str().ok()) {
+            Some(date) => date.to_string(),
+            None => {
+                let date = amz_date(SystemTime::now());
+                headers.insert(X_AMZ_DATE, HeaderValue::from_str(&date).unwrap());
+                date
+            }
+        };
+        if let Some(token) = &self.session_token {
+            // validated when decoding the options
+            headers.insert(X_AMZ_SECURITY_TOKEN, HeaderValue::from_str(token).unwrap());
+        }
+        let payload_hash = match payload {
+            Payload::Bytes(bytes) => hex_sha256(bytes),
+            Payload::Unsigned => UNSIGNED_PAYLOAD.to_string(),
+        };
+        // S3 requires the payload hash as a header, and it is the only way to
+        // indicate an unsigned payload
+        if self.service == "s3" || matches!(payload, Payload::Unsigned) {
+            headers.insert(
+                X_AMZ_CONTENT_SHA256,
+                HeaderValue::from_str(&payload_hash).unwrap(),
+            );
+        }
+
+        let (signed_headers, canonical_headers) = canonical_headers(url, headers);
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.as_str(),
+            canonical_uri(url, self.service != "s3"),
+            canonical_query(url),
+            canonical_headers,
+            signed_headers,
+            payload_hash
+        );
+        let date = &amz_date[..amz_date.len().min(8)];
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex_sha256(canonical_request.as_bytes())
+        );
+        let mut key = hmac(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), self.service.as_str(), "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&authorization).unwrap(),
+        );
+    }
+}
+
+/// Every path segment is encoded, and for services other than S3 the
+/// (already encoded) path is encoded a second time.
+fn canonical_uri(url: &Url, double_encode: bool) -> String {
+    let path = url.path();
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(|segment| {
+            if double_encode {
+                utf8_percent_encode(segment, AWS_ENCODE_SET).to_string()
+            } else {
+                aws_encode(segment)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn canonical_query(url: &Url) -> String {
+    let mut params: Vec<(String, String)> = url
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = match p.find('=') {
+                Some(i) => (&p[..i], &p[i + 1..]),
+                None => (p, ""),
+            };
+            (aws_encode(k), aws_encode(v))
+        })
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Returns `(signed_headers, canonical_headers)`. `Host` is added if the
+/// caller didn't set it, since it will be added later by `hyper`.
+fn canonical_headers(url: &Url, headers: &HeaderMap) -> (String, String) {
+    let mut names: Vec<&HeaderName> = headers.keys().collect();
+    let host = if headers.contains_key(HOST) {
+        None
+    } else {
+        names.push(&HOST);
+        let host = url.host_str().unwrap_or("");
+        Some(match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        })
+    };
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    let mut canonical = String::new();
+    for name in &names {
+        let values: Vec<String> = match &host {
+            Some(host) if **name == HOST => vec![host.clone()],
+            _ => headers
+                .get_all(*name)
+                .iter()
+                .map(|v| trim_value(&String::from_utf8_lossy(v.as_bytes())))
+                .collect(),
+        };
+        canonical.push_str(name.as_str());
+        canonical.push(':');
+        canonical.push_str(&values.join(","));
+        canonical.push('\n');
+    }
+    let signed = names
+        .iter()
+        .map(|n| n.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    (signed, canonical)
+}
+
+/// Trim and collapse sequential spaces
+fn trim_value(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn aws_encode(s: &str) -> String {
+    let decoded = percent_decode_str(s).collect::<Vec<u8>>();
+    percent_encoding::percent_encode(&decoded, AWS_ENCODE_SET).to_string()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Format a timestamp as `YYYYMMDD'T'HHMMSS'Z'`
+fn amz_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
@@ -1,6 +1,12 @@
+use std::slice;
+use std::sync::Arc;
 use std::time::Duration;
 
-use rustler::{NifResult, NifUnitEnum, NifUntaggedEnum, Term};
+use bytes::{Bytes, BytesMut};
+use reqwest::Url;
+use rustler::{Binary, Env, NifResult, NifUnitEnum, NifUntaggedEnum, OwnedEnv, Term};
+
+use crate::resource_binary::HEAP_BINARY_LIMIT;
 
 #[derive(NifUnitEnum)]
 enum Infinity {
@@ -8,14 +14,152 @@ enum Infinity {
 }
 
 #[derive(NifUntaggedEnum)]
-enum Timeout {
+enum OrInfinity {
     Infinity(Infinity),
-    Timeout(u64),
+    Value(u64),
 }
 
 pub fn maybe_timeout(t: Term) -> NifResult<Option<Duration>> {
     match t.decode()? {
-        Timeout::Infinity(_) => Ok(None),
-        Timeout::Timeout(ms) => Ok(Some(Duration::from_millis(ms))),
+        OrInfinity::Infinity(_) => Ok(None),
+        OrInfinity::Value(ms) => Ok(Some(Duration::from_millis(ms))),
+    }
+}
+
+/// A positive integer or `infinity`
+pub fn maybe_limit(t: Term) -> NifResult<Option<usize>> {
+    match t.decode()? {
+        OrInfinity::Infinity(_) => Ok(None),
+        OrInfinity::Value(0) => Err(rustler::Error::BadArg),
+        OrInfinity::Value(n) => Ok(Some(n as usize)),
     }
 }
+
+/// An `OwnedEnv` which holds on to terms whose binaries are shared with
+/// `Bytes` (see `iodata_to_chunks`). It is never cleared, and is only dropped
+/// once the last of those `Bytes` is.
+pub struct PinnedEnv(OwnedEnv);
+
+// SAFETY: the env is only used through `run` to read the terms saved in it
+// before it is shared, after which it is only kept alive and dropped
+unsafe impl Sync for PinnedEnv {}
+
+impl PinnedEnv {
+    pub fn new(env: OwnedEnv) -> Arc<PinnedEnv> {
+        Arc::new(PinnedEnv(env))
+    }
+
+    pub fn run<F, R>(&self, closure: F) -> R
+    where
+        F: for<'a> FnOnce(Env<'a>) -> R,
+    {
+        self.0.run(closure)
+    }
+}
+
+/// The memory of a binary held by a `PinnedEnv`
+struct EnvBinary {
+    _env: Arc<PinnedEnv>,
+    ptr: *const u8,
+    len: usize,
+}
+
+// SAFETY: the memory is immutable and lives as long as the env
+unsafe impl Send for EnvBinary {}
+
+impl AsRef<[u8]> for EnvBinary {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+/// Collects the parts of an iolist, sharing large binaries and copying
+/// everything else into as few chunks as possible
+struct Chunks<'e> {
+    env: &'e Arc<PinnedEnv>,
+    chunks: Vec<Bytes>,
+    small: BytesMut,
+}
+
+impl<'e> Chunks<'e> {
+    fn push_binary(&mut self, bin: &[u8]) {
+        if bin.len() <= HEAP_BINARY_LIMIT {
+            self.small.extend_from_slice(bin);
+        } else {
+            self.flush();
+            self.chunks.push(Bytes::from_owner(EnvBinary {
+                _env: self.env.clone(),
+                ptr: bin.as_ptr(),
+                len: bin.len(),
+            }));
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.small.is_empty() {
+            self.chunks.push(self.small.split().freeze());
+        }
+    }
+
+    /// `None` if the term isn't iodata. The terms still to be visited are
+    /// kept on a stack of their own, since iolists can be nested arbitrarily
+    /// deeply.
+    fn push(&mut self, term: Term) -> Option<()> {
+        let mut stack = vec![term];
+        while let Some(term) = stack.pop() {
+            if let Ok(bin) = term.decode::<Binary>() {
+                self.push_binary(&bin);
+                continue;
+            }
+            match term.list_get_cell() {
+                Ok((head, tail)) => {
+                    stack.push(tail);
+                    match head.decode::<u8>() {
+                        Ok(byte) => self.small.extend_from_slice(&[byte]),
+                        Err(_) => stack.push(head),
+                    }
+                }
+                // anything but the end of a list
+                Err(_) => {
+                    term.list_length().ok().filter(|&n| n == 0)?;
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+/// Split `iodata()` saved in `env` into chunks which can be handed to reqwest.
+/// Binaries which are reference counted are shared rather than copied, which
+/// keeps `env` alive until reqwest is done with them. `None` if the term is not
+/// valid iodata.
+pub fn iodata_to_chunks(env: &Arc<PinnedEnv>, term: Term) -> Option<Vec<Bytes>> {
+    let mut chunks = Chunks {
+        env,
+        chunks: Vec::new(),
+        small: BytesMut::new(),
+    };
+    chunks.push(term)?;
+    chunks.flush();
+    Some(chunks.chunks)
+}
+
+/// Like `iodata_to_chunks`, for a body which has to be contiguous. Only a body
+/// which consists of several parts is copied.
+pub fn iodata_to_bytes(env: &Arc<PinnedEnv>, term: Term) -> Option<Bytes> {
+    let mut chunks = iodata_to_chunks(env, term)?;
+    Some(match chunks.len() {
+        0 => Bytes::new(),
+        1 => chunks.pop().unwrap(),
+        _ => chunks.concat().into(),
+    })
+}
+
+/// `host:port`, used to key per-host state
+pub fn host_key(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or(""),
+        url.port_or_known_default().unwrap_or(0)
+    )
+}
@@ -8,11 +8,20 @@
         , start_client/2
         , stop_client/1
         , get_client/1
+        , circuit_state/1
+        , cache_purge/2
         , req/2
+        , req_all/2
+        , req_all/3
+        , download/3
+        , download/4
         , send/2
         , finish_send/1
+        , response/1
         , read/1
         , read/2
+        , set_active/2
+        , handle_ref/1
         , cancel/1
         , get/2
         , get/3
@@ -25,9 +34,13 @@
 -export_type([ client/0
              , method/0
              , req_opts/0
+             , req_all_opts/0
+             , download_opts/0
+             , progress/0
              , read_opts/0
              , resp/0
              , err/0
+             , circuit_state/0
              , handle/0
              ]).
 
@@ -36,6 +49,7 @@
 -record(handle, { inner :: erlang:nif_resource()
                 , ref :: reference()
                 , owner :: pid()
+                , duplex = false :: boolean()
                 }).
 -opaque handle() :: #handle{}.
 
@@ -45,6 +59,38 @@
                        , basic_auth => {Username::binary(), Password::binary()}
                        }.
 -type timeout_ms() :: non_neg_integer() | infinity.
+-type oauth2_config() :: #{ token_url := binary()
+                          , client_id := binary()
+                          , client_secret := binary()
+                          , scope => binary()
+                          }.
+-type retry_opts() :: #{ max_attempts => pos_integer() %% default 3
+                       , backoff => {exponential, BaseMs::non_neg_integer(), MaxMs::non_neg_integer()}
+                                  | {constant, Ms::non_neg_integer()} %% default {exponential, 100, 10000}
+                       , jitter => boolean() %% default true
+                       , on_status => [100..599] %% default [429, 502, 503]
+                       , methods => idempotent | all | [method()] %% default idempotent
+                       }.
+-type circuit_breaker_opts() :: #{ failure_threshold => pos_integer() %% default 5
+                                 , window => non_neg_integer() %% ms, default 10000
+                                 , open_duration => non_neg_integer() %% ms, default 30000
+                                 , half_open_requests => pos_integer() %% default 1
+                                 }.
+-type rate_limit_opts() :: #{ rate := number() %% requests per second
+                            , burst => pos_integer() %% default rate, rounded up
+                            , scope => client | host %% default client
+                            , max_wait => timeout_ms() %% default infinity
+                            }.
+-type hedge_opts() :: #{ delay := non_neg_integer() %% ms
+                       , max_extra => pos_integer() %% default 1
+                       }.
+-type cache_opts() :: #{ max_bytes => non_neg_integer() %% default 64 MiB
+                       , shared => boolean() %% default false
+                       , dir => binary() %% default in memory
+                       }.
+-type circuit_state() :: #{ state := closed | open | half_open
+                          , failures := non_neg_integer()
+                          }.
 -type client_opts() :: #{ identity => {Pkcs12Der::binary(), Password::binary()}
                         , follow_redirects => boolean() | non_neg_integer() %% default true
                         , additional_root_certs => [CertDer::binary()]
@@ -57,32 +103,102 @@
                         , pool_idle_timeout => timeout_ms()
                         , pool_max_idle_per_host => non_neg_integer()
                         , https_only => boolean() %% default false
+                        , http2_prior_knowledge => boolean() %% default false
                         , cookie_store => boolean() %% default false
                         , gzip => boolean() %% default false
+                        , oauth2 => oauth2_config()
+                        , netrc => boolean() | Path::binary() %% default false
+                        , retry => boolean() | retry_opts() %% default false
+                        , circuit_breaker => circuit_breaker_opts()
+                        , max_in_flight => pos_integer()
+                        , max_in_flight_per_host => pos_integer()
+                        , queue_timeout => timeout_ms() %% default infinity
+                        , rate_limit => rate_limit_opts()
+                        , cache => cache_opts()
                         }.
+-type aws_sigv4() :: #{ access_key := binary()
+                      , secret_key := binary()
+                      , session_token => binary()
+                      , region := binary()
+                      , service := binary()
+                      }.
+-type sign_opts() :: #{ algorithm := sha256 | sha512
+                      , key := iodata()
+                      , header := binary()
+                      , template := [timestamp | method | path | body | binary()]
+                      , timestamp_header => binary()
+                      , encoding => hex | base64 %% default hex
+                      }.
 -type method() :: options | get | post | put | delete | head | trace | connect | patch.
 -type header() :: {binary(), binary()}.
 -type req_opts() :: #{ url := binary()
                      , method := method()
                      , headers => [header()]
-                     , body => iodata() | stream %% default empty
-                     , response_body => complete | stream %% default complete
+                     , body => req_body() %% default empty
+                     , send_window => pos_integer()
+                     , duplex => boolean() %% default false
+                     , resume => boolean() | #{max_attempts => pos_integer()} %% default false
+                     , progress => progress_opt()
+                     , response_body => response_body() %% default complete
                      , timeout => timeout_ms()
+                     , digest_auth => {Username::binary(), Password::binary()}
+                     , aws_sigv4 => aws_sigv4()
+                     , sign => sign_opts()
+                     , retry => boolean() | retry_opts()
+                     , hedge => hedge_opts()
+                     , coalesce => boolean() | #{headers := [binary()]} %% default false
                      }.
+-type progress_opt() :: {pid(), IntervalMs::pos_integer()}
+                      | {pid(), reference(), IntervalMs::pos_integer()}.
+-type req_body() :: iodata()
+                  | stream
+                  | {file, binary()}
+                  | {file, binary(), Offset::non_neg_integer(), Length::non_neg_integer()}.
+-type response_body() :: complete
+                       | stream
+                       | {active, pos_integer()}
+                       | {file, binary()}
+                       | {file, binary(), #{mode => write | append, offset => non_neg_integer()}}.
 -type req_opts_optional() :: #{ headers => [header()]
-                              , body => iodata() | stream %% default empty
+                              , body => req_body() %% default empty
                               , timeout => timeout_ms()
-                              , body => iodata() | stream %% default empty
-                              , response_body => complete | stream %% default complete
+                              , body => req_body() %% default empty
+                              , send_window => pos_integer()
+                              , duplex => boolean() %% default false
+                              , resume => boolean() | #{max_attempts => pos_integer()} %% default false
+                              , progress => progress_opt()
+                              , response_body => response_body() %% default complete
+                              , digest_auth => {Username::binary(), Password::binary()}
+                              , aws_sigv4 => aws_sigv4()
+                              , sign => sign_opts()
+                              , retry => boolean() | retry_opts()
+                              , hedge => hedge_opts()
+                              , coalesce => boolean() | #{headers := [binary()]} %% default false
                               }.
+-type req_all_opts() :: #{ concurrency => pos_integer() | infinity %% default infinity
+                         , timeout => timeout_ms() %% default infinity
+                         }.
+-type download_opts() :: #{ segments => pos_integer() %% default 4
+                          , segment_size => pos_integer() %% default size / segments
+                          }.
+-type progress() :: #{ sent := non_neg_integer()
+                     , sent_total := non_neg_integer() | undefined
+                     , received := non_neg_integer()
+                     , received_total := non_neg_integer() | undefined
+                     }.
 -type read_opts() :: #{ period => timeout_ms()
                       , length => pos_integer()
                       }.
 -type resp() :: #{ status := 100..599
-                 , body := binary() | handle()
+                 , body := binary() | handle() | {file, binary(), non_neg_integer()}
                  , headers := [header()]
+                 , attempts => pos_integer() %% only present if retry is enabled
+                 , hedged => boolean() %% only present if hedge is set
+                 , cache => hit | miss | revalidated %% only present for cached get requests
                  }.
--type err() :: #{ code := timeout | redirect | url | connect | request | body | cancelled | unknown
+-type err() :: #{ code := timeout | redirect | url | connect | request | body | cancelled | oauth2
+                        | circuit_open | queue_timeout | rate_limited | file
+                        | unknown
                 , reason := binary()
                 }.
 -type feature() :: cookies | gzip.
@@ -108,6 +224,59 @@ make_client() ->
   make_client(#{}).
 
 %% @doc Make a new client with its own connection pool. See also {@link start_client/2}.
+%%
+%% With `http2_prior_knowledge', HTTP/2 is used without negotiating it first,
+%% including for `http' URLs.
+%%
+%% If `oauth2' is set, the client obtains an access token from `token_url'
+%% using the client credentials grant and adds it as a bearer token to every
+%% request that doesn't already have an `Authorization' header. The token is
+%% cached and refreshed in the background once three quarters of its lifetime
+%% have passed. If the server responds with `401', a new token is fetched and
+%% the request retried once (unless the request body is streamed). Failure to
+%% obtain a token results in an error with code `oauth2'.
+%%
+%% If `netrc' is set, requests without an `Authorization' header use basic
+%% authentication with the credentials for their host found in the given netrc
+%% file. `true' means `$NETRC' or `~/.netrc', which is ignored if it doesn't
+%% exist.
+%%
+%% If `retry' is set, requests are retried on connection errors, and on
+%% timeouts and responses with a status in `on_status' if their method is
+%% allowed by `methods' (by default only idempotent methods). See {@link req/2}.
+%%
+%% If `circuit_breaker' is set, the client keeps track of failures (connection
+%% errors, timeouts and `5xx' responses) per host. Once `failure_threshold'
+%% failures have occurred within `window' ms, requests to that host fail
+%% immediately with code `circuit_open' for `open_duration' ms. After that,
+%% `half_open_requests' trial requests are let through: if they all succeed the
+%% circuit closes, otherwise it opens again. See {@link circuit_state/1}.
+%%
+%% `max_in_flight' and `max_in_flight_per_host' limit the number of requests
+%% the client has in flight (until the response body is complete). Further
+%% requests are queued until a slot becomes free, or fail with code
+%% `queue_timeout' once they have waited for `queue_timeout' ms.
+%%
+%% `rate_limit' limits the rate at which requests (including retries) are sent,
+%% for the client as a whole or separately for each host. Up to `burst' requests
+%% can be sent at once, after which they are delayed so as not to exceed `rate'
+%% per second. Requests which would have to wait longer than `max_wait' ms fail
+%% immediately with code `rate_limited'.
+%%
+%% If `cache' is set, the client has an HTTP cache (RFC 9111) of at most
+%% `max_bytes'. `get' responses are stored according to `Cache-Control',
+%% `Expires' and `Vary', and stale responses are revalidated using `ETag' or
+%% `Last-Modified'. A `shared' cache does not store `private' responses, or
+%% responses to authenticated requests (including those authenticated with
+%% `oauth2' or `digest_auth') unless the response allows it (see RFC 9111 for
+%% details). Requests with unsafe methods evict the responses for
+%% their URL. For `get' requests, `resp()' includes whether the response was a
+%% `hit', a `miss' or `revalidated'. Requests using `body => stream' bypass the
+%% cache. With `response_body => stream' the response is stored once it has been
+%% read to the end. Responses are kept in memory unless `dir' is given, in which
+%% case they are stored in that directory (which is created if needed) and
+%% picked up by clients created later with the same `dir', eg. after a restart.
+%% See also {@link cache_purge/2}.
 -spec make_client(client_opts()) -> client().
 make_client(Opts) ->
   erqwest_nif:make_client(erqwest_runtime:get(), Opts).
@@ -147,6 +316,20 @@ stop_client(Name) ->
   persistent_term:erase({?MODULE, Name}),
   close_client(Client).
 
+%% @doc Returns the circuit breaker state of every host the client has made
+%% requests to, keyed by `<<"host:port">>'. Closed hosts without failures
+%% in the window may be left out. The map is empty if the client was made
+%% without the `circuit_breaker' option.
+-spec circuit_state(client() | atom()) -> #{binary() => circuit_state()}.
+circuit_state(Client) ->
+  erqwest_nif:circuit_state(get_client(Client)).
+
+%% @doc Removes the cached responses for `Url'. Does nothing if the client was
+%% made without the `cache' option.
+-spec cache_purge(client() | atom(), binary()) -> ok.
+cache_purge(Client, Url) ->
+  erqwest_nif:cache_purge(get_client(Client), Url).
+
 %% @private
 get_client(Client) when is_atom(Client) ->
   persistent_term:get({?MODULE, Client});
@@ -161,14 +344,107 @@ get_client(Client) ->
 %% finish_send/1} to stream the request body. If you set `response_body' to
 %% `stream', the `body' key in `resp()' be a `handle()' that you need to pass to
 %% `read' to consume the response body. If you decide not to consume the
-%% response body, call {@link cancel/1}.
+%% response body, call {@link cancel/1}. See {@link set_active/2} for
+%% `response_body => {active, N}'. See {@link send/2} for `send_window'.
+%%
+%% With `body => {file, Path}', the request body is read from `Path' while it is
+%% sent, or only `Length' bytes starting at `Offset' with `{file, Path, Offset,
+%% Length}'. It is treated like `body => stream' (eg. it is never retried or
+%% hedged, and can't be used with `digest_auth'), except that it is sent with a
+%% `content-length'. Errors opening or reading the file are returned with code
+%% `file'.
+%%
+%% With `response_body => {file, Path}', the response body is written to `Path'
+%% (truncating it, or appending to it with `mode => append') and `body' in
+%% `resp()' is `{file, Path, BytesWritten}'. Errors opening or writing the file
+%% are returned with code `file'. With `offset', the body is written at that
+%% position without truncating the file, and only if the response is `206'
+%% (otherwise nothing is written and an error with code `body' is returned). If
+%% `resume' is set on a `get' request, a download which is interrupted is
+%% continued with a `Range' request, up to `max_attempts' (default 3) attempts
+%% in total. `If-Range' is sent with the `ETag' or `Last-Modified' of the
+%% response, and the download starts again from the beginning if the server
+%% sends the whole body (or if there is nothing to send as `If-Range').
+%%
+%% With `duplex => true' (which requires both `body => stream' and a streamed
+%% `response_body'), the request body can still be sent after the response has
+%% arrived, for example for bidirectional streaming over HTTP/2. Call {@link
+%% response/1} to wait for the response, after which {@link send/2}, {@link
+%% finish_send/1} and `read' can be interleaved freely. If the response body
+%% is complete first, the rest of the request body is still sent until {@link
+%% finish_send/1} is called, and {@link send/2} returns any error.
+%%
+%% If `digest_auth' is set, a `401' response carrying a Digest challenge is
+%% answered automatically and only the final response is returned. The most
+%% recent challenge for each host is cached by the client so that subsequent
+%% requests can authenticate without the extra round-trip. `digest_auth' cannot
+%% be combined with `body => stream', since the request may need to be sent
+%% twice.
+%%
+%% If `aws_sigv4' is set, the request is signed using AWS Signature Version 4.
+%% The signing time is taken from the `x-amz-date' header if present. With
+%% `body => stream' the payload is not signed (`UNSIGNED-PAYLOAD'), which is
+%% only accepted by some services such as S3.
+%%
+%% If `sign' is set, an HMAC of the concatenated `template' parts is added as
+%% `header'. `timestamp' is the current unix time in seconds (sent as
+%% `timestamp_header' if given, or taken from that header if the request already
+%% has it), `method' is the upper case method, and `path' includes the query
+%% string. `body' cannot be signed when `body => stream'.
+%%
+%% `retry' overrides the client's retry policy for this request: `false'
+%% disables retries and a map overrides individual keys. Retries wait according
+%% to `backoff' (scaled by a random factor if `jitter' is set), or for the time
+%% given in a `Retry-After' header, unless that is longer than the maximum
+%% backoff. When retries are enabled, `resp()' includes the number of
+%% `attempts' made. Requests with `body => stream' are never retried.
+%%
+%% If `hedge' is set, another copy of the request is sent each time `delay' ms
+%% pass without a response, up to `max_extra' extra copies. The first response
+%% is used and the other copies are cancelled. `hedged' in `resp()' indicates
+%% whether one of the extra copies won. Only `get', `head', `options' and
+%% `trace' requests without `body => stream' can be hedged.
+%%
+%% If `coalesce' is set, a `get' or `head' request which is identical to one
+%% already in flight on the same client (also with `coalesce' set) is not sent.
+%% Instead it gets a copy of the other request's result. Requests are identical
+%% if they have the same method, URL and headers, or only the listed `headers'
+%% if given. Coalesced requests cannot use `digest_auth' or `response_body =>
+%% stream', or write the response body to a file. A request which is waiting
+%% for another one still fails with code `timeout' once its own `timeout' has
+%% passed.
+%%
+%% Warning: when `headers' is given, requests which differ in any other header
+%% share one response. Unless `<<"authorization">>' (and any other header
+%% carrying credentials, such as `<<"cookie">>') is listed, a response fetched
+%% with one caller's credentials is returned to another caller.
+%%
+%% If `progress' is set to `{Pid, Ref, IntervalMs}', `{erqwest_progress, Ref,
+%% progress()}' is sent to `Pid' every `IntervalMs' until the request is
+%% complete. With `{Pid, IntervalMs}', `Ref' is the one passed to {@link
+%% erqwest_async:req/4}, so this form is only useful with that API: the `Ref'
+%% used by this function isn't exposed. The reports are never consumed by this
+%% function, even if `Pid' is the caller. `sent' counts the bytes of the request body which have
+%% been handed to the connection (a body which isn't streamed counts as sent
+%% once the response arrives), and `received' the bytes of the response body
+%% which have been read, including those read by a resumed download.
+%% `sent_total' is `undefined' for `body => stream', and `received_total' is
+%% the `content-length' of the response if there is one. No reports are sent
+%% after the final reply.
 -spec req(client() | atom(), req_opts()) ->
         {ok, resp()} | {handle, handle()} | {error, err()}.
 req(Client, #{body := stream}=Req) ->
   Inner = erqwest_nif:req(get_client(Client), self(), Ref=make_ref(), Req),
+  Handle = #handle{ inner=Inner
+                  , ref=Ref
+                  , owner=self()
+                  , duplex=maps:get(duplex, Req, false)
+                  },
   receive
     {erqwest_response, Ref, next} ->
-      {handle, #handle{inner=Inner, ref=Ref, owner=self()}};
+      {handle, Handle};
+    {erqwest_response, Ref, next, _Credit} ->
+      {handle, Handle};
     {erqwest_response, Ref, error, Resp} ->
       {error, Resp}
   end;
@@ -176,36 +452,114 @@ req(Client, Req) ->
   Inner = erqwest_nif:req(get_client(Client), self(), Ref=make_ref(), Req),
   receive
     {erqwest_response, Ref, reply, Resp} ->
-      case Req of
-        #{response_body := stream} ->
-          {ok, Resp#{body => #handle{inner=Inner, ref=Ref, owner=self()}}};
-        #{} ->
-          {ok, Resp}
-      end;
+      {ok, maybe_stream(#handle{inner=Inner, ref=Ref, owner=self()}, Resp)};
     {erqwest_response, Ref, error, Resp} ->
       {error, Resp}
   end.
 
+%% @equiv req_all(Client, Reqs, #{})
+-spec req_all(client() | atom(), [req_opts()]) -> [{ok, resp()} | {error, err()}].
+req_all(Client, Reqs) ->
+  req_all(Client, Reqs, #{}).
+
+%% @doc Make several requests concurrently, and return their results in the
+%% same order as `Reqs' once all of them have completed. At most `concurrency'
+%% requests are in flight at once. Once `timeout' ms have passed, the requests
+%% which haven't completed yet are cancelled, and their result is `{error,
+%% #{code := cancelled}}'. Streaming (`body => stream' or `response_body =>
+%% stream') is not supported.
+%%
+%% Fails with reason badarg if any of the requests is invalid, in which case
+%% none of them are started.
+-spec req_all(client() | atom(), [req_opts()], req_all_opts()) ->
+        [{ok, resp()} | {error, err()}].
+req_all(Client, Reqs, Opts) ->
+  Handles = erqwest_nif:req_all(get_client(Client), self(), Ref=make_ref(), Reqs, Opts),
+  Deadline = case maps:get(timeout, Opts, infinity) of
+               infinity -> infinity;
+               Timeout -> erlang:monotonic_time(millisecond) + Timeout
+             end,
+  Results = collect_all(Ref, pending(Handles), Deadline, #{}),
+  [maps:get(I, Results) || I <- lists:seq(1, length(Reqs))].
+
+%% @equiv download(Client, Url, Path, #{})
+-spec download(client() | atom(), binary(), binary()) -> {ok, resp()} | {error, err()}.
+download(Client, Url, Path) ->
+  download(Client, Url, Path, #{}).
+
+%% @doc Download `Url' to `Path' in segments which are fetched concurrently.
+%% The size of the resource is found with a `head' request, then byte ranges of
+%% `segment_size' are requested with up to `segments' in flight at once, and
+%% each is written at its offset in `Path'. If the server doesn't accept ranges
+%% or doesn't report the size, the whole body is fetched with a single `get'.
+%% If the `head' request is answered with a 4xx or 5xx status, that response is
+%% returned and `Path' is left alone.
+%%
+%% Returns the response to the `head' request, with `body => {file, Path,
+%% Size}'. An error with code `body' is returned if a range isn't answered with
+%% `206' and the expected number of bytes (eg. because the resource changed in
+%% the meantime), or if the size of the file doesn't match in the end. The
+%% other ranges are cancelled as soon as one of them fails.
+-spec download(client() | atom(), binary(), binary(), download_opts()) ->
+        {ok, resp()} | {error, err()}.
+download(Client, Url, Path, Opts) ->
+  Segments = maps:get(segments, Opts, 4),
+  is_integer(Segments) andalso Segments > 0 orelse error(badarg),
+  case req(Client, #{method => head, url => Url}) of
+    {ok, #{status := 200, headers := Headers}=Head} ->
+      AcceptRanges = string:lowercase(proplists:get_value(<<"accept-ranges">>, Headers, <<>>)),
+      case content_length(Headers) of
+        Size when AcceptRanges =:= <<"bytes">>, Size =/= undefined ->
+          SegmentSize = maps:get(segment_size, Opts, max(1, ceil(Size / Segments))),
+          is_integer(SegmentSize) andalso SegmentSize > 0 orelse error(badarg),
+          download_segments(Client, Url, Path, Head, Size, Segments, SegmentSize);
+        _ ->
+          get(Client, Url, #{response_body => {file, Path}})
+      end;
+    {ok, #{status := Status}}=Resp when Status >= 400 ->
+      Resp;
+    {ok, #{}} ->
+      get(Client, Url, #{response_body => {file, Path}});
+    {error, _}=Err ->
+      Err
+  end.
+
 %% @doc Stream a chunk of the request body. Returns `ok' once the chunk has
 %% successfully been queued for transmission. Note that due to buffering this
 %% does not mean that the chunk has actually been sent. Blocks once the internal
-%% buffer is full. Call {@link finish_send/1} once the body is complete.
+%% buffer is full. If `send_window' is set, up to that many bytes are queued
+%% before blocking, so that the caller doesn't wait for each chunk to reach the
+%% connection. Call {@link finish_send/1} once the body is complete.
 %% `{reply, resp()}' is returned if the server chooses to reply before the
-%% request body is complete.
+%% request body is complete. With `duplex', the response is left for {@link
+%% response/1} instead.
 -spec send(handle(), iodata()) ->
         ok | {reply, resp()} | {error, err()}.
+send(#handle{inner=Inner, ref=Ref, duplex=true}=Handle, Data) ->
+  ?assertOwner(Handle),
+  ok = erqwest_nif:send(Inner, Data),
+  receive
+    {erqwest_response, Ref, next} -> ok;
+    {erqwest_response, Ref, next, _Credit} -> ok;
+    {erqwest_response, Ref, error, Err} -> {error, Err}
+  end;
 send(#handle{inner=Inner, ref=Ref}=Handle, Data) ->
   ?assertOwner(Handle),
   ok = erqwest_nif:send(Inner, Data),
   receive
     {erqwest_response, Ref, next} -> ok;
+    {erqwest_response, Ref, next, _Credit} -> ok;
     {erqwest_response, Ref, reply, Resp} -> {reply, maybe_stream(Handle, Resp)};
     {erqwest_response, Ref, error, Err} -> {error, Err}
   end.
 
 %% @doc Complete sending the request body. Awaits the server's reply. Return
-%% values are as described for {@link req/2}.
--spec finish_send(handle()) -> {ok, resp()} | {error, err()}.
+%% values are as described for {@link req/2}. With `duplex', returns `ok'
+%% without waiting, see {@link response/1}.
+-spec finish_send(handle()) -> ok | {ok, resp()} | {error, err()}.
+finish_send(#handle{inner=Inner, duplex=true}=Handle) ->
+  ?assertOwner(Handle),
+  ok = erqwest_nif:finish_send(Inner);
 finish_send(#handle{inner=Inner, ref=Ref}=Handle) ->
   ?assertOwner(Handle),
   erqwest_nif:finish_send(Inner),
@@ -214,6 +568,17 @@ finish_send(#handle{inner=Inner, ref=Ref}=Handle) ->
     {erqwest_response, Ref, error, Err} -> {error, Err}
   end.
 
+%% @doc Await the server's reply to a `duplex' request. The `body' of the
+%% response is the same handle, which can be passed to `read' while the request
+%% body is still being sent. Return values are as described for {@link req/2}.
+-spec response(handle()) -> {ok, resp()} | {error, err()}.
+response(#handle{ref=Ref, duplex=true}=Handle) ->
+  ?assertOwner(Handle),
+  receive
+    {erqwest_response, Ref, reply, Resp} -> {ok, maybe_stream(Handle, Resp)};
+    {erqwest_response, Ref, error, Err} -> {error, Err}
+  end.
+
 %% @equiv read(Handle, #{})
 -spec read(handle()) -> {more, binary()} | {ok, binary()} | {error, err()}.
 read(Handle) ->
@@ -235,6 +600,31 @@ read(#handle{inner=Inner, ref=Ref}=Handle, Opts) ->
     {erqwest_response, Ref, error, Err} -> {error, Err}
   end.
 
+%% @doc Send up to `N' more chunks of the response body as messages, without
+%% waiting for {@link read/2}. With `response_body => {active, N}', the first
+%% `N' chunks are sent as soon as they arrive. Like `inet''s `{active, N}', the
+%% messages are:
+%%
+%% * `{erqwest_response, Ref, chunk, binary()}' for each chunk.
+%%
+%% * `{erqwest_response, Ref, passive}' once `N' chunks have been sent, after
+%% which more need to be requested with `set_active/2' (or `read/2').
+%%
+%% * `{erqwest_response, Ref, fin, <<>>}' or `{erqwest_response, Ref, error,
+%% err()}' as the final message.
+%%
+%% `Ref' is given by {@link handle_ref/1}. Fails with reason badarg if the
+%% response body is already complete.
+-spec set_active(handle(), pos_integer()) -> ok.
+set_active(#handle{inner=Inner}=Handle, N) ->
+  ?assertOwner(Handle),
+  erqwest_nif:set_active(Inner, N).
+
+%% @doc The reference used in the messages described in {@link set_active/2}.
+-spec handle_ref(handle()) -> reference().
+handle_ref(#handle{ref=Ref}) ->
+  Ref.
+
 %% @doc Used to cancel streaming of a request or response body.
 -spec cancel(handle()) -> ok.
 cancel(#handle{inner=Inner}=Handle) ->
@@ -280,6 +670,135 @@ patch(Client, Url, Opts) ->
 
 %% internal functions
 
+download_segments(Client, Url, Path, #{headers := Headers}=Head, Size, Segments, SegmentSize) ->
+  case preallocate(Path, Size) of
+    ok ->
+      Ranges = [{Start, min(SegmentSize, Size - Start)}
+                || Start <- lists:seq(0, Size - 1, SegmentSize)],
+      Reqs = [#{ method => get
+               , url => Url
+               , headers => [{<<"range">>, range_header(Start, Length)} | if_range(Headers)]
+               , response_body => {file, Path, #{offset => Start}}
+               }
+              || {Start, Length} <- Ranges],
+      Handles = erqwest_nif:req_all(get_client(Client), self(), Ref=make_ref(), Reqs,
+                                    #{concurrency => Segments}),
+      Indexed = maps:from_list(lists:zip(lists:seq(1, length(Ranges)), Ranges)),
+      case collect_segments(Ref, pending(Handles), Indexed) of
+        ok ->
+          case filelib:file_size(Path) of
+            Size ->
+              {ok, Head#{body => {file, Path, Size}}};
+            Other ->
+              download_error("expected ~B bytes, but the file has ~B", [Size, Other])
+          end;
+        {error, _}=Err ->
+          Err
+      end;
+    {error, Reason} ->
+      {error, #{code => file, reason => iolist_to_binary(file:format_error(Reason))}}
+  end.
+
+content_length(Headers) ->
+  try binary_to_integer(proplists:get_value(<<"content-length">>, Headers)) of
+    Length when Length >= 0 -> Length;
+    _ -> undefined
+  catch
+    error:badarg -> undefined
+  end.
+
+preallocate(Path, Size) ->
+  case file:open(Path, [write, raw]) of
+    {ok, File} ->
+      try
+        {ok, Size} = file:position(File, Size),
+        file:truncate(File)
+      after
+        file:close(File)
+      end;
+    {error, _}=Err ->
+      Err
+  end.
+
+%% If the resource changes, the server sends all of it instead of a range. Weak
+%% ETags can't be used for this.
+if_range(Headers) ->
+  ETag = case proplists:get_value(<<"etag">>, Headers) of
+           <<"W/", _/binary>> -> undefined;
+           Strong -> Strong
+         end,
+  case {ETag, proplists:get_value(<<"last-modified">>, Headers)} of
+    {undefined, undefined} ->
+      [];
+    {undefined, LastModified} ->
+      [{<<"if-range">>, LastModified}];
+    {_, _} ->
+      [{<<"if-range">>, ETag}]
+  end.
+
+range_header(Start, Length) ->
+  iolist_to_binary(io_lib:format("bytes=~B-~B", [Start, Start + Length - 1])).
+
+%% Stops at the first segment which fails, and cancels the others
+collect_segments(_Ref, Pending, _Ranges) when map_size(Pending) =:= 0 ->
+  ok;
+collect_segments(Ref, Pending, Ranges) ->
+  {I, Result} = receive
+                  {erqwest_response, {Ref, I0}, reply, Resp} -> {I0, {ok, Resp}};
+                  {erqwest_response, {Ref, I0}, error, Err} -> {I0, {error, Err}}
+                end,
+  Rest = maps:remove(I, Pending),
+  case check_segment(maps:get(I, Ranges), Result) of
+    ok ->
+      collect_segments(Ref, Rest, Ranges);
+    {error, _}=Error ->
+      cancel_all(Rest),
+      %% wait for the cancelled requests so that they are done with the file
+      collect_all(Ref, Rest, infinity, #{}),
+      Error
+  end.
+
+check_segment({_, Length}, {ok, #{status := 206, body := {file, _, Length}}}) ->
+  ok;
+check_segment({Start, Length}, {ok, #{status := 206, body := {file, _, Received}}}) ->
+  download_error("expected ~B bytes in the range starting at ~B, but received ~B",
+                 [Length, Start, Received]);
+check_segment({Start, _}, {ok, #{status := Status}}) ->
+  download_error("unexpected response to the range starting at ~B: ~B", [Start, Status]);
+check_segment(_Range, {error, _}=Err) ->
+  Err.
+
+download_error(Format, Args) ->
+  {error, #{code => body, reason => iolist_to_binary(io_lib:format(Format, Args))}}.
+
+%% The handles of the requests started by `erqwest_nif:req_all', keyed by their
+%% index
+pending(Handles) ->
+  maps:from_list(lists:zip(lists:seq(1, length(Handles)), Handles)).
+
+%% Once `Deadline' has passed, the requests which are still pending are
+%% cancelled, and each of them replies with an error.
+collect_all(_Ref, Pending, _Deadline, Results) when map_size(Pending) =:= 0 ->
+  Results;
+collect_all(Ref, Pending, Deadline, Results) ->
+  receive
+    {erqwest_response, {Ref, I}, reply, Resp} ->
+      collect_all(Ref, maps:remove(I, Pending), Deadline, Results#{I => {ok, Resp}});
+    {erqwest_response, {Ref, I}, error, Err} ->
+      collect_all(Ref, maps:remove(I, Pending), Deadline, Results#{I => {error, Err}})
+  after time_left(Deadline) ->
+      cancel_all(Pending),
+      collect_all(Ref, Pending, infinity, Results)
+  end.
+
+time_left(infinity) ->
+  infinity;
+time_left(Deadline) ->
+  max(0, Deadline - erlang:monotonic_time(millisecond)).
+
+cancel_all(Pending) ->
+  lists:foreach(fun erqwest_nif:cancel/1, maps:values(Pending)).
+
 maybe_stream(_Handle, Resp) when is_map_key(body, Resp) ->
   Resp;
 maybe_stream(Handle, Resp) ->
@@ -1,9 +1,11 @@
 -module(erqwest_async).
 
 -export([ req/4
+        , req_many/3
         , send/2
         , finish_send/1
         , read/2
+        , set_active/2
         , cancel/1
         ]).
 
@@ -16,8 +18,9 @@
 %% A Response will be sent to `Pid' as follows:
 %%
 %% * If `body' is `stream', and the request was successfully initated,
-%% `{erqwest_response, Ref, next}'. See {@link send/2} and {@link finish_send/1}
-%% for how to respond. Alternatively `{erqwest_response, Ref, error,
+%% `{erqwest_response, Ref, next}', or `{erqwest_response, Ref, next, Credit}'
+%% if `send_window' is set. See {@link send/2} and {@link finish_send/1} for how
+%% to respond. Alternatively `{erqwest_response, Ref, error,
 %% erqwest:err()}'.
 %%
 %% * If `body' is omitted or is of type `iodata()', `{erqwest_response, Ref,
@@ -25,7 +28,19 @@
 %%
 %% * If `response_body' is `stream', `erqwest:resp()' will contain a handle
 %% which should be passed to {@link read/2}. Alternatively, call {@link
-%% cancel/1} if you do not intend to consume the body.
+%% cancel/1} if you do not intend to consume the body. If `response_body' is
+%% `{active, N}', the body is streamed without calls to `read/2', see {@link
+%% set_active/2}.
+%%
+%% * If `duplex' is `true', the `reply' is sent as soon as the response has
+%% arrived, even if the request body is not complete. The request body can still
+%% be sent while the response body is read, and each `send' is answered by
+%% `next' as usual.
+%%
+%% * If `progress' is set, `{erqwest_progress, Ref, erqwest:progress()}' is
+%% sent to the given pid (which may differ from `Pid') periodically until the
+%% final response has been sent, see {@link erqwest:req/2}. `Ref' is the one
+%% given in the `progress' option, if any.
 %%
 %% An `error' response is _always_ the final response. If streaming is not used,
 %% a single reply is guaranteed.
@@ -36,12 +51,32 @@
 req(Client, Pid, Ref, Req) ->
   erqwest_nif:req(erqwest:get_client(Client), Pid, Ref, Req).
 
+%% @doc Make many asynchronous requests at once. This is equivalent to calling
+%% {@link req/4} for each `{Ref, Req}', but is cheaper for large numbers of
+%% requests since they are all started by a single (dirty scheduled) NIF call.
+%% Returns the handles in the same order as `Reqs'. Fails with reason badarg if
+%% any of the requests is invalid, in which case none of them are started. If
+%% the runtime is shutting down, the requests which were already started are
+%% cancelled before `bad_runtime' is raised, and reply with an error with code
+%% `cancelled'.
+-spec req_many(erqwest:client() | atom(), pid(), [{any(), erqwest:req_opts()}]) ->
+        [handle()].
+req_many(Client, Pid, Reqs) ->
+  erqwest_nif:req_many(erqwest:get_client(Client), Pid, Reqs).
+
 %% @doc Asynchronously stream a chunk of the request body. Replies with
 %% `{erqwest_response, Ref, next}' when the connection is ready to receive more
 %% data. Replies with `{erqwest_response, Ref, error, erqwest:err()}' if
 %% something goes wrong, or `{erqwest_response, Ref, reply, erqwest:resp()}' if
 %% the server has already decided to reply. Call {@link finish_send/1} when the
 %% request body is complete.
+%%
+%% If `send_window' is set, `next' is sent as soon as the chunk is queued, as
+%% long as at most `send_window' bytes are waiting to be sent. The reply is then
+%% `{erqwest_response, Ref, next, Credit}', where `Credit' is the number of
+%% bytes which can be sent before `next' is held back. Further chunks may be
+%% sent without waiting for `next', but every `send' is answered by exactly one
+%% `next'.
 -spec send(handle(), iodata()) -> ok.
 send(Handle, Data) ->
   erqwest_nif:send(Handle, Data).
@@ -63,6 +98,15 @@ finish_send(Handle) ->
 read(Handle, Opts) ->
   erqwest_nif:read(Handle, Opts).
 
+%% @doc Send up to `N' more chunks of the response body as `{erqwest_response,
+%% Ref, chunk, Data}' messages as soon as they arrive. Once they have been
+%% sent, `{erqwest_response, Ref, passive}' is sent. The body ends with
+%% `{erqwest_response, Ref, fin, <<>>}' or `{erqwest_response, Ref, error,
+%% erqwest:err()}' as usual. See also {@link erqwest:set_active/2}.
+-spec set_active(handle(), pos_integer()) -> ok.
+set_active(Handle, N) ->
+  erqwest_nif:set_active(Handle, N).
+
 %% @doc Cancel an asynchronous request at any stage. A reply will always still
 %% be sent, either `{error, #{code := cancelled}}', another `error' or a `reply'
 %% depending on the state of the connection. Has no effect if the request has
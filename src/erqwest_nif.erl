@@ -7,10 +7,15 @@
         , stop_runtime/1
         , make_client/2
         , close_client/1
+        , circuit_state/1
+        , cache_purge/2
         , req/4
+        , req_many/3
+        , req_all/5
         , send/2
         , finish_send/1
         , read/2
+        , set_active/2
         , cancel/1
         , cancel_stream/1
         ]).
@@ -31,9 +36,14 @@ start_runtime(_Pid) -> ?nif_stub.
 stop_runtime(_Runtime) -> ?nif_stub.
 make_client(_Runtime, _Opts) -> ?nif_stub.
 close_client(_Client) -> ?nif_stub.
+circuit_state(_Client) -> ?nif_stub.
+cache_purge(_Client, _Url) -> ?nif_stub.
 req(_Client, _Pid, _Ref, _Opts) -> ?nif_stub.
+req_many(_Client, _Pid, _Reqs) -> ?nif_stub.
+req_all(_Client, _Pid, _Ref, _Reqs, _Opts) -> ?nif_stub.
 send(_Handle, _Data) -> ?nif_stub.
 finish_send(_Handle) -> ?nif_stub.
 read(_Handle, _Opts) -> ?nif_stub.
+set_active(_Handle, _N) -> ?nif_stub.
 cancel(_Handle) -> ?nif_stub.
 cancel_stream(_Handle) -> ?nif_stub.
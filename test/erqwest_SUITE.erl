@@ -107,6 +107,10 @@ groups() ->
      , bad_header_value
      , bad_url
      , bad_body
+     , large_body
+     , post_iodata
+     , body_file
+     , body_file_error
      ]}
   , {client_cert, [parallel],
      [ with_cert
@@ -134,6 +138,8 @@ groups() ->
      , async_cancel
      , async_cancel_after_response
      , async_race_requests
+     , async_req_many
+     , async_req_many_badarg
      ]}
   , {runtime, [],
      [ runtime_stopped_make_client
@@ -151,9 +157,26 @@ groups() ->
      , stream_request_early_reply
      , stream_response_success
      , stream_both_success
+     , stream_duplex
+     , stream_duplex_http2
+     , stream_duplex_badarg
+     , response_file
+     , response_file_append
+     , response_file_error
+     , response_file_resume
+     , response_file_resume_restart
+     , response_file_resume_exhausted
+     , stream_response_large
+     , stream_response_active
+     , stream_response_active_sync
      , stream_request_invalid
      , stream_request_closed
      , stream_request_backpressure
+     , stream_request_send_window
+     , stream_request_send_window_sync
+     , progress_upload
+     , progress_download
+     , progress_file
      , stream_response_closed
      , stream_request_cancel
      , stream_response_cancel
@@ -168,6 +191,106 @@ groups() ->
      , stream_response_timeout
      , stream_handle_dropped_send
      ]}
+  , {digest_auth, [parallel],
+     [ digest_auth_md5
+     , digest_auth_sha256
+     , digest_auth_reuse_nonce
+     , digest_auth_wrong_password
+     , digest_auth_stream_body
+     ]}
+  , {aws_sigv4, [parallel],
+     [ aws_sigv4_get_vanilla
+     , aws_sigv4_get_vanilla_query_order
+     , aws_sigv4_session_token
+     , aws_sigv4_s3_payload
+     , aws_sigv4_stream_unsigned
+     ]}
+  , {oauth2, [parallel],
+     [ oauth2_token_cached
+     , oauth2_retry_unauthorized
+     , oauth2_proactive_refresh
+     , oauth2_explicit_authorization
+     , oauth2_token_error
+     ]}
+  , {sign, [parallel],
+     [ sign_hmac_sha256
+     , sign_hmac_sha512_base64
+     , sign_timestamp
+     , sign_stream_body
+     ]}
+  , {netrc, [parallel],
+     [ netrc_machine
+     , netrc_default
+     , netrc_explicit_authorization
+     , netrc_missing_file
+     ]}
+  , {retry, [parallel],
+     [ retry_status
+     , retry_after
+     , retry_exhausted
+     , retry_post
+     , retry_post_allowed
+     , retry_disabled
+     , retry_stream_body
+     ]}
+  , {circuit_breaker, [parallel],
+     [ circuit_open
+     , circuit_half_open_success
+     , circuit_half_open_failure
+     , circuit_window
+     , circuit_disabled
+     ]}
+  , {limits, [parallel],
+     [ limit_max_in_flight
+     , limit_max_in_flight_per_host
+     , limit_queue_timeout
+     ]}
+  , {rate_limit, [parallel],
+     [ rate_limit_delay
+     , rate_limit_max_wait
+     , rate_limit_per_host
+     ]}
+  , {hedge, [parallel],
+     [ hedge_wins
+     , hedge_not_needed
+     , hedge_max_extra
+     , hedge_post
+     ]}
+  , {req_all, [parallel],
+     [ req_all_order
+     , req_all_concurrency
+     , req_all_timeout
+     , req_all_badarg
+     ]}
+  , {download, [parallel],
+     [ download_segments
+     , download_no_ranges
+     , download_changed
+     , download_head_error
+     ]}
+  , {coalesce, [parallel],
+     [ coalesce_identical
+     , coalesce_different_headers
+     , coalesce_header_subset
+     , coalesce_leader_cancelled
+     , coalesce_follower_timeout
+     , coalesce_stream
+     ]}
+  , {cache, [parallel],
+     [ cache_max_age
+     , cache_no_store
+     , cache_revalidate_etag
+     , cache_revalidate_last_modified
+     , cache_vary
+     , cache_purge
+     , cache_unsafe_method
+     , cache_shared_private
+     , cache_shared_oauth2
+     , cache_max_bytes
+     , cache_disk_restart
+     , cache_disk_max_bytes
+     , cache_stream
+     ]}
   , {time_nifs, [{repeat, 5}],
      [ time_nifs
      ]}
@@ -182,6 +305,20 @@ all() ->
   , {group, runtime}
   , {group, gzip}
   , {group, stream}
+  , {group, digest_auth}
+  , {group, aws_sigv4}
+  , {group, oauth2}
+  , {group, sign}
+  , {group, netrc}
+  , {group, retry}
+  , {group, circuit_breaker}
+  , {group, limits}
+  , {group, rate_limit}
+  , {group, hedge}
+  , {group, req_all}
+  , {group, download}
+  , {group, coalesce}
+  , {group, cache}
   , {group, time_nifs}
   ].
 
@@ -280,6 +417,18 @@ bad_body(_Config) ->
     erqwest:post(default, <<"https://httpbin.org/post">>,
                  #{body => [<<"this">>, is_not_iodata]}).
 
+large_body(_Config) ->
+  Expected = large_binary(),
+  Url = server:serve(fun(_) -> {200, [], Expected} end),
+  C = erqwest:make_client(),
+  {ok, #{body := Body}} = erqwest:get(C, Url),
+  <<Part:100/binary, _/binary>> = Body,
+  erqwest:close_client(C),
+  erlang:garbage_collect(),
+  %% the body outlives the client and the request
+  Expected = Body,
+  <<Part:100/binary, _/binary>> = Expected.
+
 with_cert(Config) ->
   C = erqwest:make_client(#{identity => {?config(cert, Config), ?config(pass, Config)}}),
   {ok, #{status := 200}} = erqwest:get(C, <<"https://client.badssl.com">>).
@@ -400,6 +549,29 @@ async_race_requests(_Config) ->
           || R <- maps:keys(Refs), R =/= FirstRef],
   [#{code := cancelled} = Res || Res <- Rest].
 
+async_req_many(_Config) ->
+  Url = server:serve(fun(#{path := Path}) -> {200, [], Path} end),
+  Paths = [<<"/", (integer_to_binary(I))/binary>> || I <- lists:seq(1, 100)],
+  Reqs = [{Path, #{method => get, url => <<Url/binary, Path/binary>>}} || Path <- Paths],
+  Handles = erqwest_async:req_many(default, self(), Reqs),
+  100 = length(Handles),
+  [receive {erqwest_response, Path, reply, Res} -> #{status := 200, body := Path} = Res end
+   || Path <- Paths],
+  [] = erqwest_async:req_many(default, self(), []).
+
+async_req_many_badarg(_Config) ->
+  Url = server:serve(fun(_) -> {200, [], <<>>} end),
+  Reqs = [ {Ref0=make_ref(), #{method => get, url => Url}}
+         , {make_ref(), #{method => get}}
+         ],
+  ?assertException(error, badarg, erqwest_async:req_many(default, self(), Reqs)),
+  receive
+    {erqwest_response, Ref0, _, _} ->
+      ct:fail(unexpected_response)
+  after 100 ->
+      ok
+  end.
+
 runtime_stopped_make_client(_Config) ->
   ok = application:start(erqwest),
   erqwest:make_client(),
@@ -508,6 +680,113 @@ stream_response_success(_Config) ->
   ?assertEqual(iolist_size(Data), 10000),
   ?assertException(error, badarg, erqwest:read(Handle)).
 
+post_iodata(_Config) ->
+  Url = server:serve(fun(#{body := Body}) -> {200, [], Body} end),
+  Large = large_binary(),
+  lists:foreach(
+    fun(IoData) ->
+        Expected = iolist_to_binary(IoData),
+        {ok, #{body := Expected}} = erqwest:post(default, Url, #{body => IoData})
+    end,
+    [ <<"binary">>
+    , [<<"nested ">>, [$i, $o, [<<"list">>]], <<>>, []]
+    , [<<"improper ">> | <<"list">>]
+    , [Large, <<"and more">>]
+    %% large binaries are shared with the request rather than copied
+    , Large
+    , binary:part(Large, 1000, 100000)
+    , [<<"improper ">>, Large | Large]
+    , [[] | <<>>]
+    %% built up like an accumulator, which nests as deeply as it is long
+    , lists:foldl(fun(_, Acc) -> [Acc, <<"x">>] end, [], lists:seq(1, 100000))
+    ]),
+  [{error, #{code := request}} = erqwest:post(default, Url, #{body => IoData})
+   || IoData <- [[<<"a">> | b], [<<"a">>, 256], [Large | [<<"a">> | c]]]],
+  Shared = binary:copy(<<"shared">>, 20),
+  {handle, H} = erqwest:post(default, <<"https://httpbin.org/post">>, #{body => stream}),
+  ok = erqwest:send(H, [<<"streamed ">>, [$i, $o]]),
+  ok = erqwest:send(H, [<<"data">> | <<"!">>]),
+  ok = erqwest:send(H, [$\s, Shared | Shared]),
+  {ok, #{body := Body}} = erqwest:finish_send(H),
+  Expected = <<"streamed iodata! ", Shared/binary, Shared/binary>>,
+  #{<<"data">> := Expected} = jsx:decode(Body).
+
+body_file(Config) ->
+  Url = server:serve(fun(#{body := Body}) -> {200, [], Body} end),
+  Data = large_binary(),
+  Path = priv_path(Config, "body_file"),
+  ok = file:write_file(Path, Data),
+  %% the server reads as many bytes as the content-length says
+  {ok, #{body := Data}} = erqwest:post(default, Url, #{body => {file, Path}}),
+  <<_:100/binary, Part:1000/binary, _/binary>> = Data,
+  {ok, #{body := Part}} = erqwest:post(default, Url, #{body => {file, Path, 100, 1000}}),
+  {ok, #{body := <<>>}} = erqwest:post(default, Url, #{body => {file, Path, size(Data), 0}}).
+
+body_file_error(Config) ->
+  Url = server:serve(fun(#{body := Body}) -> {200, [], Body} end),
+  Path = priv_path(Config, "body_file_error"),
+  {error, #{code := file}} = erqwest:post(default, Url, #{body => {file, Path}}),
+  ok = file:write_file(Path, <<"data">>),
+  {error, #{code := file}} = erqwest:post(default, Url, #{body => {file, Path, 5, 0}}),
+  {error, #{code := file}} = erqwest:post(default, Url, #{body => {file, Path, 2, 3}}),
+  %% a directory can be opened, but not read
+  {error, #{code := file, reason := Reason}} =
+    erqwest:post(default, Url, #{body => {file, list_to_binary(?config(priv_dir, Config))}}),
+  ?assertNotEqual(nomatch, string:find(Reason, "not a regular file")),
+  ?assertException(error, badarg,
+                   erqwest:get(default, Url, #{body => {file, Path}, hedge => #{delay => 10}})).
+
+stream_response_large(_Config) ->
+  Expected = large_binary(),
+  Url = server:serve(fun(_) -> {200, [], Expected} end),
+  {ok, #{body := H}} = erqwest:get(default, Url, #{response_body => stream}),
+  ReadAll = fun F() ->
+                case erqwest:read(H, #{length => 100000}) of
+                  {ok, Data} -> [Data];
+                  {more, Data} -> [Data | F()]
+                end
+            end,
+  Expected = iolist_to_binary(ReadAll()).
+
+stream_response_active(_Config) ->
+  {LSock, Url} = server:listen(),
+  H = erqwest_async:req(default, self(), Ref=make_ref(),
+                        #{method => get, url => Url, response_body => {active, 2}}),
+  Sock = server:accept(LSock),
+  server:read(Sock),
+  server:reply(Sock, []),
+  receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end,
+  server:send(Sock, <<"1">>),
+  receive {erqwest_response, Ref, chunk, <<"1">>} -> ok end,
+  server:send(Sock, <<"2">>),
+  receive {erqwest_response, Ref, chunk, <<"2">>} -> ok end,
+  receive {erqwest_response, Ref, passive} -> ok end,
+  server:send(Sock, <<"3">>),
+  receive {erqwest_response, Ref, _, _} -> ct:fail(unexpected_chunk) after 100 -> ok end,
+  ok = erqwest_async:set_active(H, 1),
+  receive {erqwest_response, Ref, chunk, <<"3">>} -> ok end,
+  receive {erqwest_response, Ref, passive} -> ok end,
+  server:close(Sock),
+  ok = erqwest_async:set_active(H, 1),
+  receive {erqwest_response, Ref, fin, <<>>} -> ok end,
+  ?assertException(error, badarg, erqwest_async:set_active(H, 1)).
+
+stream_response_active_sync(_Config) ->
+  Expected = large_binary(),
+  Url = server:serve(fun(_) -> {200, [], Expected} end),
+  {ok, #{body := H}} = erqwest:get(default, Url, #{response_body => {active, 1}}),
+  Ref = erqwest:handle_ref(H),
+  Loop = fun F(Acc) ->
+             receive
+               {erqwest_response, Ref, chunk, Data} -> F([Acc, Data]);
+               {erqwest_response, Ref, passive} ->
+                 ok = erqwest:set_active(H, 10),
+                 F(Acc);
+               {erqwest_response, Ref, fin, Data} -> iolist_to_binary([Acc, Data])
+             end
+         end,
+  Expected = Loop([]).
+
 stream_both_success(_Config) ->
   {LSock, Url} = server:listen(),
   {handle, H} = erqwest:post(default, Url, #{body => stream, response_body => stream}),
@@ -524,6 +803,146 @@ stream_both_success(_Config) ->
   {ok, <<>>} = erqwest:read(H),
   ?assertException(error, badarg, erqwest:read(H)).
 
+stream_duplex(_Config) ->
+  {LSock, Url} = server:listen(),
+  {handle, H} = erqwest:post(default, Url, #{ body => stream
+                                            , response_body => stream
+                                            , duplex => true
+                                            }),
+  Sock = server:accept(LSock),
+  ok = erqwest:send(H, <<"ping1">>),
+  recv_until(Sock, <<"ping1">>),
+  server:send(Sock, <<"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n">>),
+  {ok, #{status := 200, body := H}} = erqwest:response(H),
+  server:send(Sock, <<"5\r\npong1\r\n">>),
+  {more, <<"pong1">>} = erqwest:read(H, #{length => 5}),
+  %% the request body can still be sent after the response has arrived
+  ok = erqwest:send(H, <<"ping2">>),
+  recv_until(Sock, <<"ping2">>),
+  ok = erqwest:finish_send(H),
+  recv_until(Sock, <<"0\r\n\r\n">>),
+  server:send(Sock, <<"5\r\npong2\r\n0\r\n\r\n">>),
+  ReadAll = fun F() ->
+                case erqwest:read(H) of
+                  {ok, Data} -> [Data];
+                  {more, Data} -> [Data | F()]
+                end
+            end,
+  <<"pong2">> = iolist_to_binary(ReadAll()).
+
+%% HTTP/2 frame types and flags
+-define(H2_DATA, 0).
+-define(H2_HEADERS, 1).
+-define(H2_RST_STREAM, 3).
+-define(H2_SETTINGS, 4).
+-define(H2_END_STREAM, 1).
+-define(H2_ACK, 1).
+-define(H2_END_HEADERS, 4).
+
+stream_duplex_http2(_Config) ->
+  {LSock, Url} = server:listen(),
+  C = erqwest:make_client(#{http2_prior_knowledge => true}),
+  {handle, H} = erqwest:post(C, Url, #{ body => stream
+                                      , response_body => stream
+                                      , duplex => true
+                                      }),
+  Sock = server:accept(LSock),
+  {ok, <<"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n">>} = gen_tcp:recv(Sock, 24, 5000),
+  ok = gen_tcp:send(Sock, h2_frame(?H2_SETTINGS, 0, 0, <<>>)),
+  h2_await_headers(Sock),
+  ok = erqwest:send(H, <<"ping1">>),
+  %% the whole response (`:status 200' and a body) before the rest of the
+  %% request body
+  ok = gen_tcp:send(Sock, [ h2_frame(?H2_HEADERS, ?H2_END_HEADERS, 1, <<16#88>>)
+                          , h2_frame(?H2_DATA, ?H2_END_STREAM, 1, <<"pong">>)
+                          ]),
+  {ok, #{status := 200, body := H}} = erqwest:response(H),
+  {ok, <<"pong">>} = erqwest:read(H),
+  ok = erqwest:send(H, <<"ping2">>),
+  ok = erqwest:finish_send(H),
+  <<"ping1ping2">> = h2_read_body(Sock, <<>>).
+
+stream_duplex_badarg(_Config) ->
+  Url = <<"https://httpbin.org/post">>,
+  ?assertException(error, badarg,
+                   erqwest:post(default, Url, #{body => stream, duplex => true})),
+  ?assertException(error, badarg,
+                   erqwest:post(default, Url, #{response_body => stream, duplex => true})).
+
+response_file(Config) ->
+  Expected = large_binary(),
+  Url = server:serve(fun(_) -> {200, [], Expected} end),
+  Path = priv_path(Config, "response_file"),
+  Size = size(Expected),
+  {ok, #{status := 200, body := {file, Path, Size}}} =
+    erqwest:get(default, Url, #{response_body => {file, Path}}),
+  {ok, Expected} = file:read_file(Path),
+  %% a longer file is truncated, by default and with `mode => write'
+  Longer = <<Expected/binary, "trailing data">>,
+  ok = file:write_file(Path, Longer),
+  {ok, #{body := {file, Path, Size}}} =
+    erqwest:get(default, Url, #{response_body => {file, Path}}),
+  {ok, Expected} = file:read_file(Path),
+  ok = file:write_file(Path, Longer),
+  {ok, #{body := {file, Path, Size}}} =
+    erqwest:get(default, Url, #{response_body => {file, Path, #{mode => write}}}),
+  {ok, Expected} = file:read_file(Path).
+
+response_file_append(Config) ->
+  Url = server:serve(fun(_) -> {200, [], <<"data">>} end),
+  Path = priv_path(Config, "response_file_append"),
+  ok = file:write_file(Path, <<"existing ">>),
+  {ok, #{body := {file, Path, 4}}} =
+    erqwest:get(default, Url, #{response_body => {file, Path, #{mode => append}}}),
+  {ok, <<"existing data">>} = file:read_file(Path).
+
+response_file_error(Config) ->
+  Url = server:serve(fun(_) -> {200, [], <<"data">>} end),
+  Path = priv_path(Config, "missing/response_file"),
+  {error, #{code := file}} = erqwest:get(default, Url, #{response_body => {file, Path}}),
+  ?assertException(error, {bad_opt, other},
+                   erqwest:get(default, Url, #{response_body => {file, Path, #{other => 1}}})),
+  ?assertException(error, badarg,
+                   erqwest:get(default, Url, #{response_body => {file, Path, #{mode => other}}})).
+
+response_file_resume(Config) ->
+  Url = raw_server([ [ <<"HTTP/1.1 200 OK\r\ncontent-length: 10\r\netag: \"v1\"\r\n\r\n">>
+                     , <<"01234">>
+                     ]
+                   , [ <<"HTTP/1.1 206 Partial Content\r\ncontent-length: 5\r\n">>
+                     , <<"content-range: bytes 5-9/10\r\n\r\n">>
+                     , <<"56789">>
+                     ]
+                   ]),
+  Path = priv_path(Config, "response_file_resume"),
+  {ok, #{status := 200, body := {file, Path, 10}}} =
+    erqwest:get(default, Url, #{response_body => {file, Path}, resume => true}),
+  {ok, <<"0123456789">>} = file:read_file(Path),
+  receive {raw_request, _} -> ok end,
+  receive {raw_request, Req} -> ok end,
+  <<"bytes=5-">> = request_header(<<"range">>, Req),
+  <<"\"v1\"">> = request_header(<<"if-range">>, Req).
+
+response_file_resume_restart(Config) ->
+  Head = <<"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\n">>,
+  Url = raw_server([[Head, <<"01234">>], [Head, <<"0123456789">>]]),
+  Path = priv_path(Config, "response_file_resume_restart"),
+  {ok, #{body := {file, Path, 10}}} =
+    erqwest:get(default, Url, #{response_body => {file, Path}, resume => true}),
+  {ok, <<"0123456789">>} = file:read_file(Path).
+
+response_file_resume_exhausted(Config) ->
+  Head = <<"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\n">>,
+  Url = raw_server([[Head, <<"01234">>], [Head, <<"012">>]]),
+  Path = priv_path(Config, "response_file_resume_exhausted"),
+  {error, #{code := body}} =
+    erqwest:get(default, Url, #{ response_body => {file, Path}
+                               , resume => #{max_attempts => 2}
+                               }),
+  ?assertException(error, badarg, erqwest:get(default, Url, #{resume => true})),
+  ?assertException(error, badarg,
+                   erqwest:post(default, Url, #{response_body => {file, Path}, resume => true})).
+
 stream_request_invalid(_Config) ->
   {error, #{code := url}} = erqwest:post(default, <<"invalid">>, #{body => stream}).
 
@@ -543,6 +962,39 @@ stream_request_backpressure(_Config) ->
   server:close(Sock),
   receive {erqwest_response, Ref, error, _} -> ok end.
 
+stream_request_send_window(_Config) ->
+  {LSock, Url} = server:listen(),
+  H = erqwest_async:req(default, self(), Ref=make_ref(), #{ method => post
+                                                          , url => Url
+                                                          , body => stream
+                                                          , send_window => 100000
+                                                          }),
+  receive {erqwest_response, Ref, next, 100000} -> ok end,
+  %% several sends can be outstanding, each is answered with the remaining credit
+  Data = binary:copy(<<0>>, 1000),
+  [ok = erqwest_async:send(H, Data) || _ <- lists:seq(1, 10)],
+  Credits = [receive {erqwest_response, Ref, next, C} -> C end || _ <- lists:seq(1, 10)],
+  ?assert(lists:all(fun(C) -> C >= 90000 andalso C < 100000 end, Credits)),
+  %% the window is used up before the caller is blocked
+  BytesSent = send_until_blocked(H, Ref),
+  ?assert(BytesSent >= 80000),
+  Sock = server:accept(LSock),
+  server:read_silent(Sock, BytesSent),
+  server:close(Sock),
+  receive {erqwest_response, Ref, error, _} -> ok end.
+
+stream_request_send_window_sync(_Config) ->
+  {handle, H} = erqwest:post(default, <<"https://httpbin.org/post">>,
+                             #{body => stream, send_window => 1000}),
+  ok = erqwest:send(H, <<"1,">>),
+  ok = erqwest:send(H, <<"2,">>),
+  ok = erqwest:send(H, <<"3">>),
+  {ok, #{body := Body}} = erqwest:finish_send(H),
+  #{<<"data">> := <<"1,2,3">>} = jsx:decode(Body),
+  ?assertException(error, badarg,
+                   erqwest:post(default, <<"https://httpbin.org/post">>,
+                                #{body => stream, send_window => 0})).
+
 stream_request_closed(_Config) ->
   {LSock, Url} = server:listen(),
   {handle, H} = erqwest:post(default, Url, #{body => stream}),
@@ -563,6 +1015,60 @@ stream_request_cancel(_Config) ->
   server:wait_for_close(Sock),
   ?assertException(error, badarg, erqwest:send(H, <<"fail">>)).
 
+progress_upload(_Config) ->
+  {LSock, Url} = server:listen(),
+  PRef = make_ref(),
+  {handle, H} = erqwest:post(default, Url, #{body => stream, progress => {self(), PRef, 10}}),
+  Sock = server:accept(LSock),
+  ok = erqwest:send(H, <<"data">>),
+  {PRef, #{sent_total := undefined, received := 0}} =
+    await_progress(fun(#{sent := Sent}) -> Sent =:= 4 end),
+  server:close(Sock),
+  {error, _} = erqwest:finish_send(H),
+  no_more_progress().
+
+progress_download(_Config) ->
+  Data = large_binary(),
+  Size = size(Data),
+  Url = server:serve(fun(_) -> {200, [], Data} end),
+  {ok, #{body := H}} = erqwest:get(default, Url, #{ response_body => stream
+                                                  , progress => {self(), 10}
+                                                  }),
+  %% nothing is received until it is read
+  {_, #{sent := 0, sent_total := 0, received := 0}} =
+    await_progress(fun(#{received_total := Total}) -> Total =:= Size end),
+  {more, Chunk} = erqwest:read(H, #{length => 1}),
+  ChunkSize = size(Chunk),
+  await_progress(fun(#{received := Received}) -> Received =:= ChunkSize end),
+  ReadAll = fun F(Acc) ->
+                case erqwest:read(H) of
+                  {more, More} -> F(<<Acc/binary, More/binary>>);
+                  {ok, Last} -> <<Acc/binary, Last/binary>>
+                end
+            end,
+  Data = ReadAll(Chunk),
+  no_more_progress(),
+  ?assertException(error, badarg, erqwest:get(default, Url, #{progress => {self(), 0}})),
+  ?assertException(error, badarg, erqwest:get(default, Url, #{progress => self()})),
+  ?assertException(error, badarg, erqwest:get(default, Url, #{progress => {self(), foo, 10}})).
+
+progress_file(Config) ->
+  Data = large_binary(),
+  Size = size(Data),
+  Path = priv_path(Config, "progress_file"),
+  ok = file:write_file(Path, Data),
+  %% the whole body has been sent while the server takes its time to reply
+  Url = server:serve(fun(#{body := Body}) -> timer:sleep(100), {200, [], Body} end),
+  erqwest_async:req(default, self(), Ref=make_ref(), #{ method => post
+                                                      , url => Url
+                                                      , body => {file, Path}
+                                                      , progress => {self(), 10}
+                                                      }),
+  {Ref, #{sent_total := Size, received := 0, received_total := undefined}} =
+    await_progress(fun(#{sent := Sent}) -> Sent =:= Size end),
+  receive {erqwest_response, Ref, reply, #{body := Data}} -> ok end,
+  no_more_progress().
+
 stream_response_closed(_Config) ->
   {LSock, Url} = server:listen(),
   Parent = self(),
@@ -755,6 +1261,764 @@ stream_handle_dropped_send(_Config) ->
   %% final chunk is indicated by 0\r\n\r\n
   nomatch = string:find(Data, <<"0\r\n\r\n">>).
 
+digest_auth_md5(_Config) ->
+  {ok, #{status := 200, body := Body}} =
+    erqwest:get(default, <<"https://httpbin.org/digest-auth/auth/user/passwd/MD5">>,
+                #{digest_auth => {<<"user">>, <<"passwd">>}}),
+  #{<<"authenticated">> := true} = jsx:decode(Body).
+
+digest_auth_sha256(_Config) ->
+  {ok, #{status := 200, body := Body}} =
+    erqwest:get(default, <<"https://httpbin.org/digest-auth/auth/user/passwd/SHA-256">>,
+                #{digest_auth => {<<"user">>, <<"passwd">>}}),
+  #{<<"authenticated">> := true} = jsx:decode(Body).
+
+digest_auth_reuse_nonce(_Config) ->
+  {LSock, Url} = server:listen(),
+  C = erqwest:make_client(),
+  Parent = self(),
+  spawn_link(
+    fun() ->
+        Sock = server:accept(LSock),
+        {ok, Req1} = gen_tcp:recv(Sock, 0),
+        nomatch = string:find(Req1, <<"authorization">>),
+        ok = gen_tcp:send(Sock, [ "HTTP/1.1 401 Unauthorized\r\n"
+                                , "WWW-Authenticate: Digest realm=\"test\", "
+                                  "nonce=\"abc\", qop=\"auth\"\r\n"
+                                , "Content-Length: 0\r\n\r\n"
+                                ]),
+        {ok, Req2} = gen_tcp:recv(Sock, 0),
+        Parent ! {auth, Req2},
+        ok = gen_tcp:send(Sock, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"),
+        {ok, Req3} = gen_tcp:recv(Sock, 0),
+        Parent ! {auth, Req3},
+        server:reply(Sock, [<<"content-length: 0">>])
+    end),
+  {ok, #{status := 200}} = erqwest:get(C, Url, #{digest_auth => {<<"user">>, <<"pass">>}}),
+  receive {auth, Req2} -> {match, _} = re:run(Req2, "nc=00000001") end,
+  %% the second request authenticates up front with the cached nonce
+  {ok, #{status := 200}} = erqwest:get(C, Url, #{digest_auth => {<<"user">>, <<"pass">>}}),
+  receive {auth, Req3} -> {match, _} = re:run(Req3, "nc=00000002") end.
+
+digest_auth_wrong_password(_Config) ->
+  {ok, #{status := 401}} =
+    erqwest:get(default, <<"https://httpbin.org/digest-auth/auth/user/passwd/MD5">>,
+                #{digest_auth => {<<"user">>, <<"wrong">>}}).
+
+digest_auth_stream_body(_Config) ->
+  {error, #{code := request}} =
+    erqwest:post(default, <<"https://httpbin.org/digest-auth/auth/user/passwd/MD5">>,
+                 #{body => stream, digest_auth => {<<"user">>, <<"passwd">>}}).
+
+%% The following are from the AWS SigV4 test suite, see
+%% https://docs.aws.amazon.com/general/latest/gr/signature-v4-test-suite.html
+
+-define(AWS_CREDENTIALS, #{ access_key => <<"AKIDEXAMPLE">>
+                          , secret_key => <<"wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY">>
+                          , region => <<"us-east-1">>
+                          , service => <<"service">>
+                          }).
+-define(AWS_HEADERS, [ {<<"host">>, <<"example.amazonaws.com">>}
+                     , {<<"x-amz-date">>, <<"20150830T123600Z">>}
+                     ]).
+
+aws_sigv4_get_vanilla(_Config) ->
+  Req = capture_request(<<"/">>, #{ headers => ?AWS_HEADERS
+                                  , aws_sigv4 => ?AWS_CREDENTIALS
+                                  }),
+  <<"AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, "
+    "SignedHeaders=host;x-amz-date, "
+    "Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31">> =
+    request_header(<<"authorization">>, Req).
+
+aws_sigv4_get_vanilla_query_order(_Config) ->
+  Req = capture_request(<<"/?Param2=value2&Param1=value1">>,
+                        #{ headers => ?AWS_HEADERS
+                         , aws_sigv4 => ?AWS_CREDENTIALS
+                         }),
+  <<"AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, "
+    "SignedHeaders=host;x-amz-date, "
+    "Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500">> =
+    request_header(<<"authorization">>, Req).
+
+aws_sigv4_session_token(_Config) ->
+  Req = capture_request(<<"/">>, #{ aws_sigv4 => ?AWS_CREDENTIALS#{session_token => <<"token">>}
+                                  }),
+  <<"token">> = request_header(<<"x-amz-security-token">>, Req),
+  {match, _} = re:run(request_header(<<"authorization">>, Req),
+                      "SignedHeaders=host;x-amz-date;x-amz-security-token,").
+
+aws_sigv4_s3_payload(_Config) ->
+  Req = capture_request(<<"/">>, #{ method => put
+                                  , body => [<<"hello ">>, "world"]
+                                  , aws_sigv4 => ?AWS_CREDENTIALS#{service => <<"s3">>}
+                                  }),
+  Hash = string:lowercase(binary:encode_hex(crypto:hash(sha256, <<"hello world">>))),
+  Hash = request_header(<<"x-amz-content-sha256">>, Req).
+
+aws_sigv4_stream_unsigned(_Config) ->
+  {LSock, Url} = server:listen(),
+  {handle, H} = erqwest:put(default, Url, #{ body => stream
+                                           , aws_sigv4 => ?AWS_CREDENTIALS#{service => <<"s3">>}
+                                           }),
+  Sock = server:accept(LSock),
+  {ok, Req} = gen_tcp:recv(Sock, 0),
+  <<"UNSIGNED-PAYLOAD">> = request_header(<<"x-amz-content-sha256">>, Req),
+  ok = erqwest:send(H, <<"data">>),
+  server:reply(Sock, [<<"content-length: 0">>]),
+  {ok, #{status := 200}} = erqwest:finish_send(H).
+
+oauth2_token_cached(_Config) ->
+  {Url, C} = oauth2_server(3600),
+  {ok, #{status := 200, body := <<"Bearer token1">>}} = erqwest:get(C, <<Url/binary, "/resource">>),
+  {ok, #{status := 200, body := <<"Bearer token1">>}} = erqwest:get(C, <<Url/binary, "/resource">>),
+  receive
+    {token_request, #{headers := Headers, body := Body}} ->
+      <<"Basic ", Auth/binary>> = proplists:get_value(<<"authorization">>, Headers),
+      <<"id:secret">> = base64:decode(Auth),
+      #{ <<"grant_type">> := <<"client_credentials">>
+       , <<"scope">> := <<"read">>
+       } = maps:from_list(uri_string:dissect_query(Body))
+  end.
+
+oauth2_retry_unauthorized(_Config) ->
+  {Url, C} = oauth2_server(3600),
+  {ok, #{status := 200, body := <<"Bearer token1">>}} = erqwest:get(C, <<Url/binary, "/resource">>),
+  receive {token_request, _} -> ok end,
+  %% token1 is rejected, so a new token is fetched and the request retried
+  {ok, #{status := 200, body := <<"Bearer token2">>}} = erqwest:get(C, <<Url/binary, "/token2_only">>),
+  receive {token_request, _} -> ok end.
+
+oauth2_proactive_refresh(_Config) ->
+  %% the refresh is due after 3 s and the token expires after 4 s, leaving half
+  %% a second on either side for the request made in between
+  {Url, C} = oauth2_server(4),
+  {ok, #{body := <<"Bearer token1">>}} = erqwest:get(C, <<Url/binary, "/resource">>),
+  receive {token_request, _} -> ok end,
+  timer:sleep(3500),
+  %% the current token is still valid, but a new one is fetched in the background
+  {ok, #{body := <<"Bearer token1">>}} = erqwest:get(C, <<Url/binary, "/resource">>),
+  receive {token_request, _} -> ok end,
+  %% the new token is used once the background fetch has stored it
+  AwaitToken2 = fun F(Attempts) ->
+                    case erqwest:get(C, <<Url/binary, "/resource">>) of
+                      {ok, #{body := <<"Bearer token2">>}} ->
+                        ok;
+                      {ok, #{body := <<"Bearer token1">>}} when Attempts > 1 ->
+                        timer:sleep(10),
+                        F(Attempts - 1)
+                    end
+                end,
+  ok = AwaitToken2(100).
+
+oauth2_explicit_authorization(_Config) ->
+  {Url, C} = oauth2_server(3600),
+  {ok, #{status := 200, body := <<"Basic other">>}} =
+    erqwest:get(C, <<Url/binary, "/resource">>,
+                #{headers => [{<<"authorization">>, <<"Basic other">>}]}).
+
+oauth2_token_error(_Config) ->
+  Url = server:serve(fun(_) -> {400, [], <<"{\"error\":\"invalid_client\"}">>} end),
+  C = erqwest:make_client(#{oauth2 => #{ token_url => <<Url/binary, "/token">>
+                                       , client_id => <<"id">>
+                                       , client_secret => <<"wrong">>
+                                       }}),
+  {error, #{code := oauth2}} = erqwest:get(C, <<Url/binary, "/resource">>).
+
+sign_hmac_sha256(_Config) ->
+  Req = capture_request(<<"/hook?a=1">>,
+                        #{ method => post
+                         , headers => [{<<"x-timestamp">>, <<"1600000000">>}]
+                         , body => [<<"{\"event\":">>, "1}"]
+                         , sign => #{ algorithm => sha256
+                                    , key => <<"secret">>
+                                    , header => <<"x-signature">>
+                                    , timestamp_header => <<"x-timestamp">>
+                                    , template => [timestamp, <<".">>, method, <<".">>, path,
+                                                   <<".">>, body]
+                                    }
+                         }),
+  Expected = crypto:mac(hmac, sha256, <<"secret">>,
+                        <<"1600000000.POST./hook?a=1.{\"event\":1}">>),
+  Expected = binary:decode_hex(request_header(<<"x-signature">>, Req)).
+
+sign_hmac_sha512_base64(_Config) ->
+  Req = capture_request(<<"/">>,
+                        #{ method => put
+                         , body => <<"data">>
+                         , sign => #{ algorithm => sha512
+                                    , key => [<<"sec">>, "ret"]
+                                    , header => <<"x-signature">>
+                                    , template => [body]
+                                    , encoding => base64
+                                    }
+                         }),
+  Expected = crypto:mac(hmac, sha512, <<"secret">>, <<"data">>),
+  Expected = base64:decode(request_header(<<"x-signature">>, Req)).
+
+sign_timestamp(_Config) ->
+  Before = erlang:system_time(second),
+  Req = capture_request(<<"/">>,
+                        #{ sign => #{ algorithm => sha256
+                                    , key => <<"secret">>
+                                    , header => <<"x-signature">>
+                                    , timestamp_header => <<"x-timestamp">>
+                                    , template => [timestamp]
+                                    }
+                         }),
+  Timestamp = request_header(<<"x-timestamp">>, Req),
+  ?assert(binary_to_integer(Timestamp) >= Before),
+  Expected = crypto:mac(hmac, sha256, <<"secret">>, Timestamp),
+  Expected = binary:decode_hex(request_header(<<"x-signature">>, Req)).
+
+sign_stream_body(_Config) ->
+  {error, #{code := request}} =
+    erqwest:post(default, <<"https://httpbin.org/post">>,
+                 #{ body => stream
+                  , sign => #{ algorithm => sha256
+                             , key => <<"secret">>
+                             , header => <<"x-signature">>
+                             , template => [body]
+                             }
+                  }).
+
+netrc_machine(Config) ->
+  C = netrc_client(Config, "machine example.com login other password other\n"
+                           "machine localhost\n  login user\n  password pass\n"),
+  Req = capture_request(C, <<"/">>, #{}),
+  <<"Basic ", Auth/binary>> = request_header(<<"authorization">>, Req),
+  <<"user:pass">> = base64:decode(Auth).
+
+netrc_default(Config) ->
+  C = netrc_client(Config, "machine example.com login other password other\n"
+                           "default login user password pass\n"),
+  Req = capture_request(C, <<"/">>, #{}),
+  <<"Basic ", Auth/binary>> = request_header(<<"authorization">>, Req),
+  <<"user:pass">> = base64:decode(Auth).
+
+netrc_explicit_authorization(Config) ->
+  C = netrc_client(Config, "machine localhost login user password pass\n"),
+  Req = capture_request(C, <<"/">>, #{headers => [{<<"authorization">>, <<"Bearer token">>}]}),
+  <<"Bearer token">> = request_header(<<"authorization">>, Req).
+
+netrc_missing_file(Config) ->
+  Path = filename:join(?config(priv_dir, Config), "missing_netrc"),
+  ?assertException(error, {client_builder_error, _},
+                   erqwest:make_client(#{netrc => list_to_binary(Path)})).
+
+-define(RETRY_FAST, #{backoff => {constant, 10}, jitter => false}).
+
+retry_status(_Config) ->
+  {Url, Counter} = retry_server(2),
+  C = erqwest:make_client(#{retry => ?RETRY_FAST}),
+  {ok, #{status := 200, attempts := 2}} = erqwest:get(C, Url),
+  2 = counters:get(Counter, 1).
+
+retry_after(_Config) ->
+  {Url, Counter} = retry_server(2, [{<<"retry-after">>, <<"1">>}]),
+  Start = erlang:monotonic_time(millisecond),
+  {ok, #{status := 200, attempts := 2}} = erqwest:get(default, Url, #{retry => true}),
+  ?assert(erlang:monotonic_time(millisecond) - Start >= 1000),
+  2 = counters:get(Counter, 1).
+
+retry_exhausted(_Config) ->
+  {Url, Counter} = retry_server(10),
+  C = erqwest:make_client(#{retry => ?RETRY_FAST}),
+  {ok, #{status := 503, attempts := 3}} = erqwest:get(C, Url),
+  3 = counters:get(Counter, 1),
+  %% a `Retry-After' longer than the maximum backoff is not honoured
+  {Url2, Counter2} = retry_server(2, [{<<"retry-after">>, <<"60">>}]),
+  {ok, #{status := 503, attempts := 1}} = erqwest:get(C, Url2),
+  1 = counters:get(Counter2, 1).
+
+retry_post(_Config) ->
+  {Url, Counter} = retry_server(2),
+  C = erqwest:make_client(#{retry => ?RETRY_FAST}),
+  {ok, #{status := 503, attempts := 1}} = erqwest:post(C, Url, #{body => <<"data">>}),
+  1 = counters:get(Counter, 1).
+
+retry_post_allowed(_Config) ->
+  {Url, Counter} = retry_server(2),
+  C = erqwest:make_client(#{retry => ?RETRY_FAST}),
+  {ok, #{status := 200, attempts := 2}} =
+    erqwest:post(C, Url, #{body => <<"data">>, retry => #{methods => [post]}}),
+  2 = counters:get(Counter, 1).
+
+retry_disabled(_Config) ->
+  {Url, Counter} = retry_server(2),
+  C = erqwest:make_client(#{retry => ?RETRY_FAST}),
+  {ok, #{status := 503}=Resp} = erqwest:get(C, Url, #{retry => false}),
+  false = maps:is_key(attempts, Resp),
+  1 = counters:get(Counter, 1).
+
+retry_stream_body(_Config) ->
+  {Url, Counter} = retry_server(2),
+  C = erqwest:make_client(#{retry => ?RETRY_FAST}),
+  {handle, Handle} = erqwest:put(C, Url, #{body => stream}),
+  {ok, #{status := 503, attempts := 1}} = erqwest:finish_send(Handle),
+  1 = counters:get(Counter, 1).
+
+circuit_open(_Config) ->
+  {Url, Counter} = retry_server(infinity),
+  C = erqwest:make_client(#{circuit_breaker => #{failure_threshold => 2}}),
+  Host = circuit_host(Url),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  #{Host := #{state := closed, failures := 1}} = erqwest:circuit_state(C),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  {error, #{code := circuit_open}} = erqwest:get(C, Url),
+  #{Host := #{state := open}} = erqwest:circuit_state(C),
+  2 = counters:get(Counter, 1).
+
+circuit_half_open_success(_Config) ->
+  {Url, Counter} = retry_server(2),
+  C = erqwest:make_client(#{circuit_breaker => #{failure_threshold => 1, open_duration => 100}}),
+  Host = circuit_host(Url),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  {error, #{code := circuit_open}} = erqwest:get(C, Url),
+  timer:sleep(150),
+  #{Host := #{state := half_open}} = erqwest:circuit_state(C),
+  {ok, #{status := 200}} = erqwest:get(C, Url),
+  #{Host := #{state := closed, failures := 0}} = erqwest:circuit_state(C),
+  2 = counters:get(Counter, 1).
+
+circuit_half_open_failure(_Config) ->
+  {Url, Counter} = retry_server(infinity),
+  C = erqwest:make_client(#{circuit_breaker => #{failure_threshold => 1, open_duration => 100}}),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  timer:sleep(150),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  {error, #{code := circuit_open}} = erqwest:get(C, Url),
+  2 = counters:get(Counter, 1).
+
+circuit_window(_Config) ->
+  {Url, _Counter} = retry_server(infinity),
+  C = erqwest:make_client(#{circuit_breaker => #{failure_threshold => 2, window => 100}}),
+  Host = circuit_host(Url),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  timer:sleep(150),
+  {ok, #{status := 503}} = erqwest:get(C, Url),
+  #{Host := #{state := closed, failures := 1}} = erqwest:circuit_state(C).
+
+circuit_disabled(_Config) ->
+  {Url, _Counter} = retry_server(infinity),
+  {ok, #{status := 503}} = erqwest:get(default, Url),
+  0 = map_size(erqwest:circuit_state(default)).
+
+limit_max_in_flight(_Config) ->
+  [?assertException(error, badarg, erqwest:make_client(#{K => N}))
+   || K <- [max_in_flight, max_in_flight_per_host], N <- [0, 1 bsl 62]],
+  Url = gated_server(),
+  C = erqwest:make_client(#{max_in_flight => 1}),
+  Ref1 = async_get(C, <<Url/binary, "/1">>),
+  Ref2 = async_get(C, <<Url/binary, "/2">>),
+  Pid1 = receive {request, _, Pid} -> Pid end,
+  receive {request, _, _} -> ct:fail(limit_exceeded) after 100 -> ok end,
+  Pid1 ! go,
+  receive {request, _, Pid2} -> Pid2 ! go end,
+  [receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end || Ref <- [Ref1, Ref2]].
+
+limit_max_in_flight_per_host(_Config) ->
+  UrlA = gated_server(),
+  UrlB = gated_server(),
+  C = erqwest:make_client(#{max_in_flight_per_host => 1}),
+  RefA1 = async_get(C, <<UrlA/binary, "/a1">>),
+  RefA2 = async_get(C, <<UrlA/binary, "/a2">>),
+  RefB = async_get(C, <<UrlB/binary, "/b">>),
+  %% a1 or a2, and b
+  Pids = [receive {request, _, Pid} -> Pid end || _ <- [1, 2]],
+  receive {request, _, _} -> ct:fail(limit_exceeded) after 100 -> ok end,
+  [Pid ! go || Pid <- Pids],
+  receive {request, _, Pid3} -> Pid3 ! go end,
+  [receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end
+   || Ref <- [RefA1, RefA2, RefB]].
+
+limit_queue_timeout(_Config) ->
+  Url = gated_server(),
+  C = erqwest:make_client(#{max_in_flight => 1, queue_timeout => 100}),
+  Ref = async_get(C, Url),
+  Pid = receive {request, _, P} -> P end,
+  {error, #{code := queue_timeout}} = erqwest:get(C, Url),
+  Pid ! go,
+  receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end.
+
+rate_limit_delay(_Config) ->
+  {Url, _Counter} = retry_server(1),
+  C = erqwest:make_client(#{rate_limit => #{rate => 10, burst => 1}}),
+  Start = erlang:monotonic_time(millisecond),
+  [{ok, #{status := 200}} = erqwest:get(C, Url) || _ <- [1, 2, 3]],
+  %% the first request is sent immediately
+  ?assert(erlang:monotonic_time(millisecond) - Start >= 190).
+
+rate_limit_max_wait(_Config) ->
+  {Url, Counter} = retry_server(1),
+  C = erqwest:make_client(#{rate_limit => #{rate => 1, max_wait => 100}}),
+  {ok, #{status := 200}} = erqwest:get(C, Url),
+  {error, #{code := rate_limited}} = erqwest:get(C, Url),
+  1 = counters:get(Counter, 1),
+  %% the next token is too far off to wait for, even without `max_wait'
+  Slow = erqwest:make_client(#{rate_limit => #{rate => 1.0e-300}}),
+  {ok, #{status := 200}} = erqwest:get(Slow, Url),
+  {error, #{code := rate_limited}} = erqwest:get(Slow, Url).
+
+rate_limit_per_host(_Config) ->
+  {UrlA, _} = retry_server(1),
+  {UrlB, _} = retry_server(1),
+  C = erqwest:make_client(#{rate_limit => #{rate => 1, scope => host, max_wait => 0}}),
+  {ok, #{status := 200}} = erqwest:get(C, UrlA),
+  {ok, #{status := 200}} = erqwest:get(C, UrlB),
+  {error, #{code := rate_limited}} = erqwest:get(C, UrlA).
+
+hedge_wins(_Config) ->
+  {Url, Counter} = slow_server(1, 1000),
+  Start = erlang:monotonic_time(millisecond),
+  {ok, #{status := 200, body := <<"2">>, hedged := true}} =
+    erqwest:get(default, Url, #{hedge => #{delay => 50}}),
+  ?assert(erlang:monotonic_time(millisecond) - Start < 1000),
+  2 = atomics:get(Counter, 1).
+
+hedge_not_needed(_Config) ->
+  {Url, Counter} = slow_server(0, 0),
+  {ok, #{status := 200, body := <<"1">>, hedged := false}} =
+    erqwest:get(default, Url, #{hedge => #{delay => 1000}}),
+  1 = atomics:get(Counter, 1).
+
+hedge_max_extra(_Config) ->
+  {Url, Counter} = slow_server(3, 300),
+  {ok, #{status := 200, body := <<"1">>, hedged := false}} =
+    erqwest:get(default, Url, #{hedge => #{delay => 20, max_extra => 2}}),
+  3 = atomics:get(Counter, 1).
+
+hedge_post(_Config) ->
+  {Url, _Counter} = slow_server(0, 0),
+  ?assertError(badarg, erqwest:post(default, Url, #{body => <<>>, hedge => #{delay => 10}})).
+
+coalesce_identical(_Config) ->
+  Url = gated_server(),
+  Refs = [coalesce_get(Url, [], true) || _ <- [1, 2, 3]],
+  receive {request, _, Pid} -> Pid ! go end,
+  [receive {erqwest_response, Ref, reply, #{status := 200, body := <<"/">>}} -> ok end
+   || Ref <- Refs],
+  receive {request, _, _} -> ct:fail(not_coalesced) after 100 -> ok end.
+
+coalesce_follower_timeout(_Config) ->
+  Url = gated_server(),
+  Ref = coalesce_get(Url, [], true),
+  receive {request, _, Pid} -> ok end,
+  {error, #{code := timeout}} = erqwest:get(default, Url, #{coalesce => true, timeout => 100}),
+  Pid ! go,
+  receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end,
+  receive {request, _, _} -> ct:fail(not_coalesced) after 100 -> ok end.
+
+coalesce_different_headers(_Config) ->
+  Url = gated_server(),
+  Ref1 = coalesce_get(Url, [{<<"authorization">>, <<"Bearer a">>}], true),
+  Ref2 = coalesce_get(Url, [{<<"authorization">>, <<"Bearer b">>}], true),
+  [receive {request, _, Pid} -> Pid ! go end || _ <- [1, 2]],
+  [receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end || Ref <- [Ref1, Ref2]].
+
+coalesce_header_subset(_Config) ->
+  Url = gated_server(),
+  Opts = #{headers => [<<"accept">>]},
+  Ref1 = coalesce_get(Url, [{<<"accept">>, <<"text/plain">>}, {<<"x-id">>, <<"1">>}], Opts),
+  Ref2 = coalesce_get(Url, [{<<"accept">>, <<"text/plain">>}, {<<"x-id">>, <<"2">>}], Opts),
+  receive {request, _, Pid} -> Pid ! go end,
+  [receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end || Ref <- [Ref1, Ref2]],
+  receive {request, _, _} -> ct:fail(not_coalesced) after 100 -> ok end.
+
+coalesce_leader_cancelled(_Config) ->
+  Url = gated_server(),
+  Leader = erqwest_async:req(default, self(), LeaderRef = make_ref(),
+                             #{method => get, url => Url, coalesce => true}),
+  receive {request, _, Pid1} -> ok end,
+  Ref = coalesce_get(Url, [], true),
+  ok = erqwest_async:cancel(Leader),
+  receive {erqwest_response, LeaderRef, error, #{code := cancelled}} -> ok end,
+  %% the follower sends the request itself
+  receive {request, _, Pid2} -> Pid2 ! go end,
+  receive {erqwest_response, Ref, reply, #{status := 200}} -> ok end,
+  Pid1 ! go.
+
+coalesce_stream(_Config) ->
+  ?assertError(badarg, erqwest:get(default, <<"http://localhost">>,
+                                   #{coalesce => true, response_body => stream})),
+  ?assertError(badarg, erqwest:post(default, <<"http://localhost">>,
+                                    #{coalesce => true, body => <<>>})),
+  %% the credentials wouldn't be part of the key
+  ?assertError(badarg, erqwest:get(default, <<"http://localhost">>,
+                                   #{coalesce => true, digest_auth => {<<"user">>, <<"pass">>}})).
+
+cache_max_age(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"max-age=60">>}]),
+  C = erqwest:make_client(#{cache => #{}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(C, Url),
+  {ok, #{body := <<"1">>, cache := hit, headers := Headers}} = erqwest:get(C, Url),
+  true = lists:keymember(<<"age">>, 1, Headers),
+  %% the request can ask for a fresher response
+  {ok, #{body := <<"2">>, cache := miss}} =
+    erqwest:get(C, Url, #{headers => [{<<"cache-control">>, <<"no-cache">>}]}).
+
+cache_no_store(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"no-store">>}]),
+  C = erqwest:make_client(#{cache => #{}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(C, Url),
+  {ok, #{body := <<"2">>, cache := miss}} = erqwest:get(C, Url).
+
+cache_revalidate_etag(_Config) ->
+  Url = cache_server(fun(#{headers := Headers}, N) ->
+                         case proplists:get_value(<<"if-none-match">>, Headers) of
+                           <<"\"v1\"">> -> {304, [{<<"x-n">>, integer_to_binary(N)}], <<>>};
+                           undefined -> {200, [ {<<"etag">>, <<"\"v1\"">>}
+                                              , {<<"cache-control">>, <<"no-cache">>}
+                                              ], <<"body">>}
+                         end
+                     end),
+  C = erqwest:make_client(#{cache => #{}}),
+  {ok, #{status := 200, body := <<"body">>, cache := miss}} = erqwest:get(C, Url),
+  {ok, #{status := 200, body := <<"body">>, cache := revalidated, headers := Headers}} =
+    erqwest:get(C, Url),
+  %% headers are updated from the 304
+  {<<"x-n">>, <<"2">>} = lists:keyfind(<<"x-n">>, 1, Headers).
+
+cache_revalidate_last_modified(_Config) ->
+  LastModified = <<"Wed, 21 Oct 2015 07:28:00 GMT">>,
+  Url = cache_server(fun(#{headers := Headers}, _N) ->
+                         case proplists:get_value(<<"if-modified-since">>, Headers) of
+                           LastModified -> {304, [], <<>>};
+                           undefined -> {200, [ {<<"last-modified">>, LastModified}
+                                              , {<<"cache-control">>, <<"max-age=0">>}
+                                              ], <<"body">>}
+                         end
+                     end),
+  C = erqwest:make_client(#{cache => #{}}),
+  {ok, #{body := <<"body">>, cache := miss}} = erqwest:get(C, Url),
+  {ok, #{body := <<"body">>, cache := revalidated}} = erqwest:get(C, Url).
+
+cache_vary(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"max-age=60">>}, {<<"vary">>, <<"accept">>}]),
+  C = erqwest:make_client(#{cache => #{}}),
+  Get = fun(Accept) -> erqwest:get(C, Url, #{headers => [{<<"accept">>, Accept}]}) end,
+  {ok, #{body := <<"1">>, cache := miss}} = Get(<<"text/plain">>),
+  {ok, #{body := <<"2">>, cache := miss}} = Get(<<"text/html">>),
+  {ok, #{body := <<"1">>, cache := hit}} = Get(<<"text/plain">>),
+  {ok, #{body := <<"2">>, cache := hit}} = Get(<<"text/html">>).
+
+cache_purge(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"max-age=60">>}]),
+  C = erqwest:make_client(#{cache => #{}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(C, Url),
+  ok = erqwest:cache_purge(C, <<Url/binary, "/#fragment">>),
+  {ok, #{body := <<"2">>, cache := miss}} = erqwest:get(C, Url),
+  ok = erqwest:cache_purge(default, Url).
+
+cache_unsafe_method(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"max-age=60">>}]),
+  C = erqwest:make_client(#{cache => #{}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(C, Url),
+  {ok, #{body := <<"2">>} = Resp} = erqwest:post(C, Url, #{body => <<>>}),
+  false = maps:is_key(cache, Resp),
+  {ok, #{body := <<"3">>, cache := miss}} = erqwest:get(C, Url).
+
+cache_shared_private(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"private, max-age=60">>}]),
+  Shared = erqwest:make_client(#{cache => #{shared => true}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(Shared, Url),
+  {ok, #{body := <<"2">>, cache := miss}} = erqwest:get(Shared, Url),
+  Private = erqwest:make_client(#{cache => #{shared => false}}),
+  {ok, #{body := <<"3">>, cache := miss}} = erqwest:get(Private, Url),
+  {ok, #{body := <<"3">>, cache := hit}} = erqwest:get(Private, Url).
+
+cache_shared_oauth2(_Config) ->
+  Url = cache_server(fun(#{path := <<"/token">>}, _N) ->
+                         {200, [{<<"content-type">>, <<"application/json">>}],
+                          jsx:encode(#{ access_token => <<"token">>
+                                      , token_type => <<"Bearer">>
+                                      , expires_in => 3600
+                                      })};
+                        (_Req, N) ->
+                         {200, [{<<"cache-control">>, <<"max-age=60">>}], integer_to_binary(N)}
+                     end),
+  C = erqwest:make_client(#{ cache => #{shared => true}
+                           , oauth2 => #{ token_url => <<Url/binary, "/token">>
+                                        , client_id => <<"id">>
+                                        , client_secret => <<"secret">>
+                                        }
+                           }),
+  %% the bearer token is added after the lookup, but still counts
+  {ok, #{body := <<"2">>, cache := miss}} = erqwest:get(C, <<Url/binary, "/resource">>),
+  {ok, #{body := <<"3">>, cache := miss}} = erqwest:get(C, <<Url/binary, "/resource">>).
+
+cache_max_bytes(_Config) ->
+  Url = cache_server([{<<"cache-control">>, <<"max-age=60">>}]),
+  C = erqwest:make_client(#{cache => #{max_bytes => 10}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(C, Url),
+  {ok, #{body := <<"2">>, cache := miss}} = erqwest:get(C, Url).
+
+cache_disk_restart(Config) ->
+  Dir = cache_dir(Config, "cache_disk_restart"),
+  Url = cache_server([{<<"cache-control">>, <<"max-age=60">>}]),
+  C0 = erqwest:make_client(#{cache => #{dir => Dir}}),
+  {ok, #{body := <<"1">>, cache := miss}} = erqwest:get(C0, Url),
+  erqwest:close_client(C0),
+  C1 = erqwest:make_client(#{cache => #{dir => Dir}}),
+  {ok, #{body := <<"1">>, cache := hit}} = erqwest:get(C1, Url),
+  erqwest:close_client(C1),
+  %% leftovers of an interrupted write are cleaned up
+  ok = file:write_file(filename:join(Dir, "leftover.tmp"), <<"partial">>),
+  C2 = erqwest:make_client(#{cache => #{dir => Dir}}),
+  {ok, #{body := <<"1">>, cache := hit}} = erqwest:get(C2, Url),
+  [] = filelib:wildcard("*.tmp", binary_to_list(Dir)).
+
+cache_disk_max_bytes(Config) ->
+  Dir = cache_dir(Config, "cache_disk_max_bytes"),
+  Url = cache_server(fun(_, N) ->
+                         {200, [{<<"cache-control">>, <<"max-age=60">>}],
+                          binary:copy(integer_to_binary(N), 1000)}
+                     end),
+  Opts = #{cache => #{dir => Dir, max_bytes => 1500}},
+  C0 = erqwest:make_client(Opts),
+  {ok, #{cache := miss}} = erqwest:get(C0, <<Url/binary, "/a">>),
+  {ok, #{cache := miss}} = erqwest:get(C0, <<Url/binary, "/b">>),
+  %% /a was evicted to make room for /b
+  [_] = filelib:wildcard("*.entry", binary_to_list(Dir)),
+  erqwest:close_client(C0),
+  C1 = erqwest:make_client(Opts),
+  {ok, #{cache := hit}} = erqwest:get(C1, <<Url/binary, "/b">>),
+  {ok, #{cache := miss}} = erqwest:get(C1, <<Url/binary, "/a">>).
+
+cache_stream(Config) ->
+  Body = binary:copy(<<"0123456789">>, 1000),
+  Url = cache_server(fun(_, _N) ->
+                         {200, [{<<"cache-control">>, <<"max-age=60">>}], Body}
+                     end),
+  ReadAll = fun F(Handle) ->
+                case erqwest:read(Handle, #{length => 100}) of
+                  {ok, Data} -> Data;
+                  {more, Data} -> <<Data/binary, (F(Handle))/binary>>
+                end
+            end,
+  lists:foreach(
+    fun(Cache) ->
+        C = erqwest:make_client(#{cache => Cache}),
+        %% stored as it is read
+        {ok, #{body := H0, cache := miss}} = erqwest:get(C, Url, #{response_body => stream}),
+        Body = ReadAll(H0),
+        {ok, #{body := Body, cache := hit}} = erqwest:get(C, Url),
+        {ok, #{body := H1, cache := hit}} = erqwest:get(C, Url, #{response_body => stream}),
+        Body = ReadAll(H1)
+    end,
+    [#{}, #{dir => cache_dir(Config, "cache_stream")}]).
+
+req_all_order(_Config) ->
+  Url = delay_server(),
+  Reqs = [#{method => get, url => <<Url/binary, "/", Ms/binary>>}
+          || Ms <- [<<"300">>, <<"0">>, <<"100">>]],
+  [ {ok, #{status := 200, body := <<"300">>}}
+  , {ok, #{status := 200, body := <<"0">>}}
+  , {ok, #{status := 200, body := <<"100">>}}
+  ] = erqwest:req_all(default, Reqs).
+
+req_all_concurrency(_Config) ->
+  Url = gated_server(),
+  Reqs = [#{method => get, url => <<Url/binary, "/", (integer_to_binary(I))/binary>>}
+          || I <- [1, 2, 3]],
+  Self = self(),
+  spawn_link(fun() -> Self ! {results, erqwest:req_all(default, Reqs, #{concurrency => 2})} end),
+  Pids = [receive {request, _, Pid} -> Pid end || _ <- [1, 2]],
+  receive {request, _, _} -> ct:fail(limit_exceeded) after 100 -> ok end,
+  [Pid ! go || Pid <- Pids],
+  receive {request, _, Pid3} -> Pid3 ! go end,
+  receive
+    {results, Results} ->
+      [ {ok, #{body := <<"/1">>}}
+      , {ok, #{body := <<"/2">>}}
+      , {ok, #{body := <<"/3">>}}
+      ] = Results
+  end.
+
+req_all_timeout(_Config) ->
+  Url = delay_server(),
+  Reqs = [#{method => get, url => <<Url/binary, "/", Ms/binary>>}
+          || Ms <- [<<"0">>, <<"5000">>]],
+  [ {ok, #{status := 200}}
+  , {error, #{code := cancelled}}
+  ] = erqwest:req_all(default, Reqs, #{timeout => 500}),
+  %% the request which is still queued behind the slow one is cancelled too
+  [ {error, #{code := cancelled}}
+  , {error, #{code := cancelled}}
+  ] = erqwest:req_all(default, lists:reverse(Reqs), #{timeout => 500, concurrency => 1}),
+  receive {erqwest_response, _, _, _}=Msg -> ct:fail({unexpected, Msg}) after 100 -> ok end.
+
+req_all_badarg(_Config) ->
+  Url = delay_server(),
+  [] = erqwest:req_all(default, []),
+  ?assertException(error, badarg,
+                   erqwest:req_all(default, [ #{method => get, url => Url}
+                                            , #{method => post, url => Url, body => stream}
+                                            ])),
+  ?assertException(error, badarg,
+                   erqwest:req_all(default, [#{method => get, url => Url}],
+                                   #{concurrency => 0})).
+
+download_segments(Config) ->
+  Data = large_binary(),
+  Url = range_server(Data, true),
+  Path = priv_path(Config, "download_segments"),
+  Size = size(Data),
+  {ok, #{status := 200, body := {file, Path, Size}}} =
+    erqwest:download(default, Url, Path, #{segments => 4, segment_size => 1000000}),
+  {ok, Data} = file:read_file(Path),
+  Ranges = [receive {range, Range} -> Range end || _ <- lists:seq(0, Size - 1, 1000000)],
+  <<"bytes=0-999999">> = hd(lists:sort(Ranges)),
+  %% the default is one segment per connection
+  {ok, #{body := {file, Path, Size}}} = erqwest:download(default, Url, Path),
+  {ok, Data} = file:read_file(Path),
+  ?assertException(error, badarg, erqwest:download(default, Url, Path, #{segments => 0})).
+
+download_no_ranges(Config) ->
+  Url = server:serve(fun(#{method := 'HEAD'}) -> {200, [{<<"content-length">>, <<"4">>}], <<>>};
+                        (#{method := 'GET'}) -> {200, [], <<"data">>}
+                     end),
+  Path = priv_path(Config, "download_no_ranges"),
+  {ok, #{status := 200, body := {file, Path, 4}}} = erqwest:download(default, Url, Path),
+  {ok, <<"data">>} = file:read_file(Path),
+  %% a malformed size is treated as missing
+  Url2 = server:serve(fun(#{method := 'HEAD'}) ->
+                          {200, [ {<<"accept-ranges">>, <<"bytes">>}
+                                , {<<"content-length">>, <<"4x">>}
+                                ], <<>>};
+                         (#{method := 'GET'}) -> {200, [], <<"more">>}
+                      end),
+  {ok, #{status := 200, body := {file, Path, 4}}} = erqwest:download(default, Url2, Path),
+  {ok, <<"more">>} = file:read_file(Path).
+
+download_changed(Config) ->
+  Url = range_server(<<"0123456789">>, false),
+  Path = priv_path(Config, "download_changed"),
+  {error, #{code := body}} = erqwest:download(default, Url, Path, #{segment_size => 5}),
+  %% the whole body isn't written at the offset of either segment
+  {ok, <<0:80>>} = file:read_file(Path),
+  {error, #{code := body}} =
+    erqwest:get(default, Url, #{response_body => {file, Path, #{offset => 2}}}),
+  {ok, <<0:80>>} = file:read_file(Path),
+  %% a range with the wrong number of bytes
+  Short = server:serve(fun(#{method := 'HEAD'}) ->
+                           {200, [ {<<"accept-ranges">>, <<"bytes">>}
+                                 , {<<"content-length">>, <<"10">>}
+                                 ], <<>>};
+                          (#{method := 'GET'}) -> {206, [], <<"0">>}
+                       end),
+  {error, #{code := body, reason := Reason}} =
+    erqwest:download(default, Short, Path, #{segment_size => 5}),
+  {match, _} = re:run(Reason, "expected 5 bytes").
+
+download_head_error(Config) ->
+  Url = server:serve(fun(#{method := 'HEAD'}) -> {404, [], <<>>};
+                        (#{method := 'GET'}) -> {404, [], <<"not found">>}
+                     end),
+  Path = priv_path(Config, "download_head_error"),
+  ok = file:write_file(Path, <<"old">>),
+  {ok, #{status := 404}} = erqwest:download(default, Url, Path),
+  {ok, <<"old">>} = file:read_file(Path).
 
 %% This test case doesn't fail, it's just for the logs. You probably want to run
 %% it with a release build of the NIF.
@@ -782,6 +2046,8 @@ time_nifs(_Config) ->
                               , body => BigBody
                               })
          end),
+  ManyReqs = [{no_ref, #{method => get, url => <<"invalid">>}} || _ <- lists:seq(1, 1000)],
+  ?timed(fun() -> erqwest_nif:req_many(Client, self(), ManyReqs) end),
   ManyHeaders =
     [{<<"test">>,
       <<"the quick brown fox jumped over the lazy dog and a 64 byte-long binary ",
@@ -836,6 +2102,161 @@ time_nifs(_Config) ->
 
 %% helpers
 
+netrc_client(Config, Contents) ->
+  Path = filename:join(?config(priv_dir, Config),
+                       "netrc_" ++ integer_to_list(erlang:unique_integer([positive]))),
+  ok = file:write_file(Path, Contents),
+  erqwest:make_client(#{netrc => list_to_binary(Path)}).
+
+capture_request(Path, Opts) ->
+  capture_request(default, Path, Opts).
+
+%% Make a request to a local server and return the raw request head it received
+capture_request(Client, Path, Opts) ->
+  {LSock, Url} = server:listen(),
+  Parent = self(),
+  spawn_link(fun() ->
+                 Sock = server:accept(LSock),
+                 {ok, Data} = gen_tcp:recv(Sock, 0),
+                 server:reply(Sock, [<<"content-length: 0">>]),
+                 Parent ! {request, Data}
+             end),
+  {ok, #{status := 200}} =
+    erqwest:req(Client, maps:merge(#{method => get}, Opts#{url => <<Url/binary, Path/binary>>})),
+  receive {request, Data} -> Data end.
+
+retry_server(SucceedOn) ->
+  retry_server(SucceedOn, []).
+
+%% Responds with 503 (and `FailHeaders') until the `SucceedOn'th request
+%% (`infinity' never succeeds)
+retry_server(SucceedOn, FailHeaders) ->
+  Counter = counters:new(1, []),
+  Url = server:serve(fun(_) ->
+                         counters:add(Counter, 1, 1),
+                         case counters:get(Counter, 1) >= SucceedOn of
+                           true -> {200, [], <<"ok">>};
+                           false -> {503, FailHeaders, <<>>}
+                         end
+                     end),
+  {Url, Counter}.
+
+%% Each request is reported as `{request, Path, Pid}' and blocks until `Pid' is
+%% sent `go'
+gated_server() ->
+  Parent = self(),
+  server:serve(fun(#{path := Path}) ->
+                   Parent ! {request, Path, self()},
+                   receive go -> {200, [], Path} end
+               end).
+
+coalesce_get(Url, Headers, Coalesce) ->
+  erqwest_async:req(default, self(), Ref = make_ref(),
+                    #{method => get, url => Url, headers => Headers, coalesce => Coalesce}),
+  Ref.
+
+async_get(Client, Url) ->
+  erqwest_async:req(Client, self(), Ref = make_ref(), #{method => get, url => Url}),
+  Ref.
+
+%% The first `Slow' requests take `Ms' to respond. The body is the number of
+%% the request.
+slow_server(Slow, Ms) ->
+  Counter = atomics:new(1, []),
+  Url = server:serve(fun(_) ->
+                         N = atomics:add_get(Counter, 1, 1),
+                         N =< Slow andalso timer:sleep(Ms),
+                         {200, [], integer_to_binary(N)}
+                     end),
+  {Url, Counter}.
+
+%% 10 MB which aren't all the same
+large_binary() ->
+  << <<(I rem 251)>> || I <- lists:seq(1, 10 * 1024 * 1024) >>.
+
+%% Serves `Data', answering range requests if `Ranges' is true (and with all of
+%% `Data' otherwise, as if it had changed). The requested ranges are sent to the
+%% calling process.
+range_server(Data, Ranges) ->
+  Parent = self(),
+  Size = integer_to_binary(size(Data)),
+  server:serve(
+    fun(#{method := 'HEAD'}) ->
+        {200, [ {<<"accept-ranges">>, <<"bytes">>}
+              , {<<"etag">>, <<"\"v1\"">>}
+              , {<<"content-length">>, Size}
+              ], <<>>};
+       (#{method := 'GET', headers := Headers}) when Ranges ->
+        <<"bytes=", Range/binary>> = Value = proplists:get_value(<<"range">>, Headers),
+        <<"\"v1\"">> = proplists:get_value(<<"if-range">>, Headers),
+        Parent ! {range, Value},
+        [First, Last] = [binary_to_integer(B) || B <- binary:split(Range, <<"-">>)],
+        ContentRange = io_lib:format("bytes ~B-~B/~s", [First, Last, Size]),
+        {206, [{<<"content-range">>, ContentRange}], binary:part(Data, First, Last - First + 1)};
+       (#{method := 'GET'}) ->
+        {200, [], Data}
+    end).
+
+%% Responds to `/Ms' after `Ms' milliseconds, with `Ms' as the body.
+delay_server() ->
+  server:serve(fun(#{path := <<"/", Ms/binary>>}) ->
+                   timer:sleep(binary_to_integer(Ms)),
+                   {200, [], Ms}
+               end).
+
+%% `Handler(Req, N)' is called with the number of the request. A list of headers
+%% means respond with those headers and the number as the body.
+cache_server(Headers) when is_list(Headers) ->
+  cache_server(fun(_, N) -> {200, Headers, integer_to_binary(N)} end);
+cache_server(Handler) ->
+  Counter = atomics:new(1, []),
+  server:serve(fun(Req) -> Handler(Req, atomics:add_get(Counter, 1, 1)) end).
+
+cache_dir(Config, Name) ->
+  priv_path(Config, Name).
+
+priv_path(Config, Name) ->
+  list_to_binary(filename:join(?config(priv_dir, Config), Name)).
+
+%% The key used by `erqwest:circuit_state/1'
+circuit_host(<<"http://", HostPort/binary>>) ->
+  HostPort.
+
+%% Token endpoint that hands out token1, token2, ... and resources which echo
+%% the Authorization header. `/token2_only' rejects any other token.
+oauth2_server(ExpiresIn) ->
+  Self = self(),
+  Counter = counters:new(1, []),
+  Url = server:serve(
+          fun(#{path := <<"/token">>}=Req) ->
+              Self ! {token_request, Req},
+              counters:add(Counter, 1, 1),
+              {200, [{<<"content-type">>, <<"application/json">>}],
+               jsx:encode(#{ access_token => <<"token", (integer_to_binary(counters:get(Counter, 1)))/binary>>
+                           , token_type => <<"Bearer">>
+                           , expires_in => ExpiresIn
+                           })};
+             (#{path := <<"/token2_only">>, headers := Headers}) ->
+              case proplists:get_value(<<"authorization">>, Headers) of
+                <<"Bearer token2">> = Auth -> {200, [], Auth};
+                _ -> {401, [], <<>>}
+              end;
+             (#{headers := Headers}) ->
+              {200, [], proplists:get_value(<<"authorization">>, Headers)}
+          end),
+  C = erqwest:make_client(#{oauth2 => #{ token_url => <<Url/binary, "/token">>
+                                       , client_id => <<"id">>
+                                       , client_secret => <<"secret">>
+                                       , scope => <<"read">>
+                                       }}),
+  {Url, C}.
+
+request_header(Name, Req) ->
+  [_ | Lines] = binary:split(Req, <<"\r\n">>, [global]),
+  hd([Value || Line <- Lines,
+               [K, Value] <- [binary:split(Line, <<": ">>)],
+               string:lowercase(K) =:= Name]).
+
 have_tinyproxy() ->
   case exec:run("which tinyproxy", [sync]) of
     {ok, _} -> true;
@@ -879,6 +2300,102 @@ eval_new_process(Fun, Env) ->
          ),
   {ok, _} = exec:run(iolist_to_binary(Cmd), [sync, {env, Env}, {stdout, print}, {stderr, print}]).
 
+%% Reply to consecutive connections with `Responses', closing each connection
+%% after the response, and forward the requests to the calling process
+raw_server(Responses) ->
+  {ok, LSock} = gen_tcp:listen(0, [binary, {packet, raw}, {active, false}]),
+  {ok, Port} = inet:port(LSock),
+  Parent = self(),
+  spawn_link(fun() ->
+                 lists:foreach(
+                   fun(Resp) ->
+                       {ok, Sock} = gen_tcp:accept(LSock),
+                       {ok, Req} = gen_tcp:recv(Sock, 0),
+                       Parent ! {raw_request, Req},
+                       ok = gen_tcp:send(Sock, Resp),
+                       ok = gen_tcp:close(Sock)
+                   end,
+                   Responses)
+             end),
+  <<"http://localhost:", (integer_to_binary(Port))/binary>>.
+
+recv_until(Sock, Pattern) ->
+  recv_until(Sock, Pattern, <<>>).
+
+%% Just enough HTTP/2 for a single request on stream 1
+
+h2_frame(Type, Flags, StreamId, Payload) ->
+  [<<(iolist_size(Payload)):24, Type:8, Flags:8, 0:1, StreamId:31>>, Payload].
+
+h2_recv(Sock) ->
+  {ok, <<Length:24, Type:8, Flags:8, _:1, StreamId:31>>} = gen_tcp:recv(Sock, 9, 5000),
+  {ok, Payload} = case Length of
+                    0 -> {ok, <<>>};
+                    _ -> gen_tcp:recv(Sock, Length, 5000)
+                  end,
+  ct:log("h2 frame ~B on stream ~B: ~p", [Type, StreamId, Payload]),
+  {Type, Flags, StreamId, Payload}.
+
+%% Frames which the test doesn't care about, except that settings must be
+%% acknowledged
+h2_other(Sock, {?H2_SETTINGS, 0, 0, _}) ->
+  ok = gen_tcp:send(Sock, h2_frame(?H2_SETTINGS, ?H2_ACK, 0, <<>>));
+h2_other(_Sock, {?H2_RST_STREAM, _, 1, _}=Frame) ->
+  ct:fail({reset, Frame});
+h2_other(_Sock, _Frame) ->
+  ok.
+
+h2_await_headers(Sock) ->
+  case h2_recv(Sock) of
+    {?H2_HEADERS, _, 1, _} -> ok;
+    Frame -> h2_other(Sock, Frame), h2_await_headers(Sock)
+  end.
+
+%% The request body, up to the end of the stream
+h2_read_body(Sock, Acc) ->
+  case h2_recv(Sock) of
+    {?H2_DATA, Flags, 1, Data} when Flags band ?H2_END_STREAM =/= 0 ->
+      <<Acc/binary, Data/binary>>;
+    {?H2_DATA, _, 1, Data} ->
+      h2_read_body(Sock, <<Acc/binary, Data/binary>>);
+    Frame ->
+      h2_other(Sock, Frame),
+      h2_read_body(Sock, Acc)
+  end.
+
+recv_until(Sock, Pattern, Acc0) ->
+  {ok, Data} = gen_tcp:recv(Sock, 0, 5000),
+  Acc = <<Acc0/binary, Data/binary>>,
+  case binary:match(Acc, Pattern) of
+    nomatch -> recv_until(Sock, Pattern, Acc);
+    _ -> ct:log("read ~s", [Acc])
+  end.
+
+%% Waits for a progress report for which `Pred' returns true
+await_progress(Pred) ->
+  receive
+    {erqwest_progress, Ref, Progress} ->
+      case Pred(Progress) of
+        true -> {Ref, Progress};
+        false -> await_progress(Pred)
+      end
+  after 1000 ->
+      error(no_progress)
+  end.
+
+%% Progress reports stop with the final reply, so there is nothing left after
+%% flushing the ones which are already there
+no_more_progress() ->
+  receive
+    {erqwest_progress, _, _} -> no_more_progress()
+  after 0 ->
+      receive
+        {erqwest_progress, _, _}=Msg -> error({unexpected, Msg})
+      after 50 ->
+          ok
+      end
+  end.
+
 send_until_blocked(AsyncHandle, Ref) ->
   Data = list_to_binary([0 || _ <- lists:seq(1, 1000)]),
   send_until_blocked(AsyncHandle, Ref, Data, 0).
@@ -888,6 +2405,8 @@ send_until_blocked(AsyncHandle, Ref, Data, Sent) ->
   erqwest_async:send(AsyncHandle, Data),
   receive
     {erqwest_response, Ref, next} ->
+      send_until_blocked(AsyncHandle, Ref, Data, Sent + size(Data));
+    {erqwest_response, Ref, next, _Credit} ->
       send_until_blocked(AsyncHandle, Ref, Data, Sent + size(Data))
   after 100 ->
       ct:log("send_until_blocked: stopped after ~B bytes", [Sent]),
@@ -9,6 +9,7 @@
         , send/2
         , close/1
         , wait_for_close/1
+        , serve/1
         ]).
 
 listen() ->
@@ -60,3 +61,75 @@ drain(Sock, Acc) ->
     {error, closed} ->
       lists:reverse(Acc)
   end.
+
+%% Start a server that accepts any number of connections, each of which can
+%% carry several (keep-alive) requests. `Handler' is called (in the connection's
+%% process) with `#{method, path, headers, body}' and returns `{Status, Headers,
+%% Body}'. Header names are lowercase binaries. A `content-length' header is
+%% added unless the handler sets one. The server stops when the calling process
+%% exits.
+serve(Handler) ->
+  {LSock, Url} = listen(),
+  spawn_link(fun() -> accept_loop(LSock, Handler) end),
+  Url.
+
+accept_loop(LSock, Handler) ->
+  case gen_tcp:accept(LSock) of
+    {ok, Sock} ->
+      Pid = spawn(fun() ->
+                      receive go -> ok end,
+                      handle_requests(Sock, Handler)
+                  end),
+      ok = gen_tcp:controlling_process(Sock, Pid),
+      Pid ! go,
+      accept_loop(LSock, Handler);
+    {error, closed} ->
+      ok
+  end.
+
+handle_requests(Sock, Handler) ->
+  ok = inet:setopts(Sock, [{packet, http_bin}]),
+  case gen_tcp:recv(Sock, 0) of
+    {ok, {http_request, Method, {abs_path, Path}, _}} ->
+      Headers = recv_headers(Sock, []),
+      ok = inet:setopts(Sock, [{packet, raw}]),
+      Body = case proplists:get_value(<<"content-length">>, Headers) of
+               undefined -> <<>>;
+               <<"0">> -> <<>>;
+               Len ->
+                 {ok, Data} = gen_tcp:recv(Sock, binary_to_integer(Len)),
+                 Data
+             end,
+      Req = #{method => Method, path => Path, headers => Headers, body => Body},
+      ct:log("serve ~p", [Req]),
+      {Status, RespHeaders, RespBody} = Handler(Req),
+      %% the handler may set the content-length itself, eg. for a head request
+      Length = case proplists:is_defined(<<"content-length">>, RespHeaders) of
+                 true -> [];
+                 false -> ["content-length: ", integer_to_list(iolist_size(RespBody)), "\r\n"]
+               end,
+      Resp = [ "HTTP/1.1 ", integer_to_list(Status), " Status\r\n"
+             , [[K, ": ", V, "\r\n"] || {K, V} <- RespHeaders]
+             , Length, "\r\n"
+             , RespBody
+             ],
+      case gen_tcp:send(Sock, Resp) of
+        ok -> handle_requests(Sock, Handler);
+        {error, _} -> ok
+      end;
+    {error, _} ->
+      ok
+  end.
+
+recv_headers(Sock, Acc) ->
+  case gen_tcp:recv(Sock, 0) of
+    {ok, {http_header, _, Name, _, Value}} ->
+      recv_headers(Sock, [{header_name(Name), Value}|Acc]);
+    {ok, http_eoh} ->
+      lists:reverse(Acc)
+  end.
+
+header_name(Name) when is_atom(Name) ->
+  string:lowercase(atom_to_binary(Name, utf8));
+header_name(Name) ->
+  string:lowercase(Name).